@@ -39,27 +39,84 @@ const decodeMessage = async (message :any):Promise<string> => {
 }
 
 
+// Room membership announced by each client with {"type":"join","room":...,"from":...}
+interface Member {
+    room?: string;
+    peerId?: string;
+}
+
+const parseSignal = (text: string): any => {
+    try {
+        const json = JSON.parse(text);
+        return typeof json === 'object' && json !== null ? json : undefined;
+    } catch {
+        return undefined;
+    }
+};
+
 // Create WebSocket server
 const wss = new Server({ server });
+const members = new Map<WebSocket, Member>();
 
 wss.on('connection', (ws) => {
     console.log('New client connected');
+    members.set(ws, {});
 
-    ws.on('message', async (message) => {
+    ws.on('message', async (message, isBinary) => {
         console.log(`Received message of type: ${typeof message}`);
         console.log(`Detailed type: ${Object.prototype.toString.call(message)}`);
 
-        const decodedString = await decodeMessage(message);
+        // Binary frames (protobuf / CBOR etc.) are relayed as-is
+        const payload = isBinary ? message : await decodeMessage(message);
+        const signal = isBinary ? undefined : parseSignal(payload as string);
+        const sender = members.get(ws) ?? {};
+
+        // Heartbeat pings are answered by the server itself and never relayed,
+        // so a lone client still gets a pong and peers don't keep each other alive
+        if (signal?.type === 'ping') {
+            ws.send(JSON.stringify({ type: 'pong' }));
+            return;
+        }
+
+        if (signal?.type === 'join') {
+            sender.room = signal.room;
+            sender.peerId = signal.from;
+        } else if (signal?.type === 'leave') {
+            sender.room = undefined;
+        }
+
         wss.clients.forEach((client) => {
             // Send message to all clients except the sender
-            if (client !== ws && client.readyState === WebSocket.OPEN) {
-                client.send(decodedString);
+            if (client === ws || client.readyState !== WebSocket.OPEN) {
+                return;
+            }
+            const member = members.get(client) ?? {};
+            // Messages tagged with a room only go to members of that room
+            if (signal?.room && member.room !== signal.room) {
+                return;
+            }
+            // Messages addressed to a peer only go to that peer
+            if (signal?.to && member.peerId !== signal.to) {
+                return;
             }
+            client.send(payload, { binary: isBinary });
         });
 
     });
 
     ws.on('close', () => {
         console.log('Client disconnected');
+        const member = members.get(ws);
+        members.delete(ws);
+        if (!member?.room || !member.peerId) {
+            return;
+        }
+        // Tell the rest of the room that this peer is gone
+        const leave = JSON.stringify({ type: 'leave', room: member.room, from: member.peerId });
+        members.forEach((other, client) => {
+            if (other.room === member.room && client.readyState === WebSocket.OPEN) {
+                client.send(leave);
+            }
+        });
     });
-});
\ No newline at end of file
+});
@@ -0,0 +1,90 @@
+use serde::Serialize;
+
+// WebSocket のクローズコードの分類 (RFC 6455 7.4.1)
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub enum CloseKind {
+    // 1000: 正常終了
+    Normal,
+    // 1001: サーバー停止やページ遷移
+    GoingAway,
+    // 1008: ポリシー違反 (認証エラーなど)
+    PolicyViolation,
+    // 1006: クローズフレームなしで切断された
+    Abnormal,
+    Other,
+}
+
+impl CloseKind {
+    pub fn from_code(code: u16) -> CloseKind {
+        match code {
+            1000 => CloseKind::Normal,
+            1001 => CloseKind::GoingAway,
+            1008 => CloseKind::PolicyViolation,
+            1006 => CloseKind::Abnormal,
+            _ => CloseKind::Other,
+        }
+    }
+
+    // 正常終了とポリシー違反は再接続しても意味が無いので対象外
+    // 1001 はサーバー再起動の可能性があるため再接続する
+    pub fn should_reconnect(self) -> bool {
+        !matches!(self, CloseKind::Normal | CloseKind::PolicyViolation)
+    }
+}
+
+// on_close コールバックに渡す情報
+#[derive(Debug, Clone, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct CloseInfo {
+    pub code: u16,
+    pub reason: String,
+    pub was_clean: bool,
+    pub kind: CloseKind,
+    pub will_reconnect: bool,
+}
+
+// ブラウザの WebSocket.close() が受け付けるコードと理由か確認する
+pub fn validate_close(code: u16, reason: &str) -> Result<(), String> {
+    if code != 1000 && !(3000..=4999).contains(&code) {
+        return Err(format!("close code must be 1000 or in 3000-4999, got {}", code));
+    }
+    if reason.len() > 123 {
+        return Err(format!("close reason must be at most 123 bytes of UTF-8, got {}", reason.len()));
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn close_code_boundaries() {
+        assert!(validate_close(999, "").is_err());
+        assert!(validate_close(1000, "").is_ok());
+        assert!(validate_close(1001, "").is_err());
+        assert!(validate_close(2999, "").is_err());
+        assert!(validate_close(3000, "").is_ok());
+        assert!(validate_close(4999, "").is_ok());
+        assert!(validate_close(5000, "").is_err());
+    }
+
+    #[test]
+    fn reason_is_limited_to_123_bytes() {
+        assert!(validate_close(1000, &"a".repeat(123)).is_ok());
+        assert!(validate_close(1000, &"a".repeat(124)).is_err());
+        // 文字数ではなく UTF-8 のバイト数で数える ("あ" は 3 バイト)
+        assert!(validate_close(1000, &"あ".repeat(41)).is_ok());
+        assert!(validate_close(1000, &format!("{}a", "あ".repeat(41))).is_err());
+    }
+
+    #[test]
+    fn reconnect_only_for_unexpected_closes() {
+        assert!(!CloseKind::from_code(1000).should_reconnect());
+        assert!(!CloseKind::from_code(1008).should_reconnect());
+        assert!(CloseKind::from_code(1001).should_reconnect());
+        assert!(CloseKind::from_code(1006).should_reconnect());
+        assert!(CloseKind::from_code(4000).should_reconnect());
+    }
+}
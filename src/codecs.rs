@@ -0,0 +1,359 @@
+use js_sys::{Function, Reflect};
+use serde::{Deserialize, Serialize};
+use wasm_bindgen::prelude::*;
+use web_sys::RtcRtpTransceiver;
+use crate::signaling::TrackKind;
+
+// RTCRtpSender.getCapabilities() の codecs の1件分
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct CodecInfo {
+    pub mime_type: String,
+    pub clock_rate: u32,
+    #[serde(default)]
+    pub channels: Option<u16>,
+    #[serde(default)]
+    pub sdp_fmtp_line: Option<String>,
+}
+
+// 再送・冗長化用でコーデックとして選ぶものではない
+const AUXILIARY_CODECS: [&str; 4] = ["rtx", "red", "ulpfec", "flexfec-03"];
+
+// 種類ごとのコーデックの優先順 ("video/h264" のように小文字)。None ならブラウザの既定
+#[derive(Debug, Clone, Default)]
+pub(crate) struct CodecOrder {
+    audio: Option<Vec<String>>,
+    video: Option<Vec<String>>,
+}
+
+impl CodecOrder {
+    pub fn get(&self, kind: TrackKind) -> Option<&[String]> {
+        match kind {
+            TrackKind::Audio => self.audio.as_deref(),
+            TrackKind::Video => self.video.as_deref(),
+        }
+    }
+
+    pub fn set(&mut self, kind: TrackKind, order: Option<Vec<String>>) {
+        match kind {
+            TrackKind::Audio => self.audio = order,
+            TrackKind::Video => self.video = order,
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.audio.is_none() && self.video.is_none()
+    }
+}
+
+// 接続ごとの設定。トランシーバー単位の指定は種類ごとの指定より優先する
+#[derive(Default)]
+pub(crate) struct CodecPreferences {
+    pub order: CodecOrder,
+    pub transceivers: Vec<(RtcRtpTransceiver, Vec<String>)>,
+}
+
+impl CodecPreferences {
+    pub fn for_transceiver(&self, transceiver: &RtcRtpTransceiver, kind: TrackKind) -> Option<&[String]> {
+        self.transceivers
+            .iter()
+            .find(|(candidate, _)| js_sys::Object::is(candidate, transceiver))
+            .map(|(_, order)| order.as_slice())
+            .or_else(|| self.order.get(kind))
+    }
+
+    // SDP の m= セクション用 (mid で対応するトランシーバーを探す)
+    pub fn for_section(&self, kind: TrackKind, mid: Option<&str>) -> Option<&[String]> {
+        mid.and_then(|mid| {
+            self.transceivers
+                .iter()
+                .find(|(transceiver, _)| transceiver.mid().as_deref() == Some(mid))
+                .map(|(_, order)| order.as_slice())
+        })
+        .or_else(|| self.order.get(kind))
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.order.is_empty() && self.transceivers.is_empty()
+    }
+}
+
+// ブラウザが送信できるコーデック (rtx などの補助的なものは除く)
+pub(crate) fn available(kind: TrackKind) -> Vec<CodecInfo> {
+    capability_codecs("RTCRtpSender", kind)
+        .into_iter()
+        .filter_map(|codec| serde_wasm_bindgen::from_value::<CodecInfo>(codec).ok())
+        .filter(|codec| !AUXILIARY_CODECS.contains(&mime_subtype(&codec.mime_type).as_str()))
+        .collect()
+}
+
+// "H264" や "video/H264" を "video/h264" にそろえ、ブラウザが対応していないものは除く
+pub(crate) fn normalize(kind: TrackKind, mime_types: Vec<String>) -> Result<Vec<String>, String> {
+    let prefix = format!("{}/", kind.as_str());
+    let mut names = Vec::new();
+    for mime_type in mime_types {
+        let name = mime_type.trim().to_ascii_lowercase();
+        let name = if name.contains('/') { name } else { format!("{}{}", prefix, name) };
+        if !name.starts_with(&prefix) {
+            return Err(format!("{} is not an {} codec", mime_type, kind.as_str()));
+        }
+        if !names.contains(&name) {
+            names.push(name);
+        }
+    }
+    if names.is_empty() {
+        return Ok(names);
+    }
+
+    let mut supported: Vec<String> = Vec::new();
+    for class in ["RTCRtpSender", "RTCRtpReceiver"] {
+        supported.extend(capability_codecs(class, kind).iter().map(codec_mime_type));
+    }
+    // getCapabilities が無いブラウザでは確認できないので、そのまま SDP の並べ替えに使う
+    if supported.is_empty() {
+        return Ok(names);
+    }
+    let known: Vec<String> = names.into_iter().filter(|name| supported.contains(name)).collect();
+    if known.is_empty() {
+        return Err(format!("none of the preferred {} codecs are supported by this browser", kind.as_str()));
+    }
+    Ok(known)
+}
+
+// RTCRtpTransceiver.setCodecPreferences があるか
+pub(crate) fn can_set_preferences() -> bool {
+    Reflect::get(&js_sys::global(), &"RTCRtpTransceiver".into())
+        .and_then(|class| Reflect::get(&class, &"prototype".into()))
+        .and_then(|prototype| Reflect::has(&prototype, &"setCodecPreferences".into()))
+        .unwrap_or(false)
+}
+
+// 受信側の対応コーデックを preferred の順に並べ替えて setCodecPreferences に渡す
+// preferred が空なら既定の順に戻す
+pub(crate) fn apply(transceiver: &RtcRtpTransceiver, kind: TrackKind, preferred: &[String]) -> Result<(), JsValue> {
+    let set = Reflect::get(transceiver, &"setCodecPreferences".into())?.dyn_into::<Function>()?;
+    let codecs = js_sys::Array::new();
+    if !preferred.is_empty() {
+        let mut available = capability_codecs("RTCRtpReceiver", kind);
+        available.sort_by_key(|codec| rank(&codec_mime_type(codec), preferred));
+        for codec in available {
+            codecs.push(&codec);
+        }
+    }
+    // 不正な指定のときは例外になるので call で呼ぶ
+    set.call1(transceiver, &codecs)?;
+    Ok(())
+}
+
+// setCodecPreferences が無いブラウザ向けに、m= 行のペイロードタイプを優先順に並べ替える
+pub(crate) fn reorder_sdp(sdp: &str, preferred_for: impl Fn(TrackKind, Option<&str>) -> Option<Vec<String>>) -> String {
+    let line_end = if sdp.contains("\r\n") { "\r\n" } else { "\n" };
+    let lines: Vec<&str> = sdp.split(line_end).collect();
+    let mut output: Vec<String> = Vec::with_capacity(lines.len());
+    let mut index = 0;
+    while index < lines.len() && !lines[index].starts_with("m=") {
+        output.push(lines[index].to_string());
+        index += 1;
+    }
+    while index < lines.len() {
+        let start = index;
+        index += 1;
+        while index < lines.len() && !lines[index].starts_with("m=") {
+            index += 1;
+        }
+        output.extend(reorder_section(&lines[start..index], &preferred_for));
+    }
+    output.join(line_end)
+}
+
+fn reorder_section(section: &[&str], preferred_for: &impl Fn(TrackKind, Option<&str>) -> Option<Vec<String>>) -> Vec<String> {
+    let unchanged = || section.iter().map(|line| line.to_string()).collect();
+    let media_line = section[0];
+    let kind = match media_line.strip_prefix("m=").and_then(|rest| rest.split(' ').next()) {
+        Some("audio") => TrackKind::Audio,
+        Some("video") => TrackKind::Video,
+        _ => return unchanged(),
+    };
+    let mid = section.iter().find_map(|line| line.strip_prefix("a=mid:"));
+    let Some(preferred) = preferred_for(kind, mid).filter(|preferred| !preferred.is_empty()) else {
+        return unchanged();
+    };
+
+    // a=rtpmap:<payload type> <name>/<clock rate>[/<channels>]
+    let payload_mime_types: Vec<(&str, String)> = section
+        .iter()
+        .filter_map(|line| line.strip_prefix("a=rtpmap:"))
+        .filter_map(|rest| {
+            let (payload_type, codec) = rest.split_once(' ')?;
+            let name = codec.split('/').next()?;
+            Some((payload_type, format!("{}/{}", kind.as_str(), name.to_ascii_lowercase())))
+        })
+        .collect();
+
+    // m=<media> <port> <proto> <payload types...>
+    let mut fields: Vec<&str> = media_line.split(' ').collect();
+    if fields.len() <= 3 {
+        return unchanged();
+    }
+    let mut payload_types = fields.split_off(3);
+    payload_types.sort_by_key(|payload_type| {
+        payload_mime_types
+            .iter()
+            .find(|(candidate, _)| candidate == payload_type)
+            .map_or(preferred.len(), |(_, mime_type)| rank(mime_type, &preferred))
+    });
+    fields.extend(payload_types);
+
+    let mut lines = vec![fields.join(" ")];
+    lines.extend(section[1..].iter().map(|line| line.to_string()));
+    lines
+}
+
+// preferred の中の位置 (含まれなければ最後)
+fn rank(mime_type: &str, preferred: &[String]) -> usize {
+    preferred.iter().position(|name| name == mime_type).unwrap_or(preferred.len())
+}
+
+// RTCRtpSender / RTCRtpReceiver の getCapabilities(kind).codecs
+// API が無い環境では空
+fn capability_codecs(class: &str, kind: TrackKind) -> Vec<JsValue> {
+    let codecs = Reflect::get(&js_sys::global(), &class.into())
+        .and_then(|class| {
+            let get = Reflect::get(&class, &"getCapabilities".into())?.dyn_into::<Function>()?;
+            get.call1(&class, &kind.as_str().into())
+        })
+        .and_then(|capabilities| {
+            if capabilities.is_null() || capabilities.is_undefined() {
+                return Ok(JsValue::UNDEFINED);
+            }
+            Reflect::get(&capabilities, &"codecs".into())
+        });
+    match codecs.map(|codecs| codecs.dyn_into::<js_sys::Array>()) {
+        Ok(Ok(codecs)) => codecs.iter().collect(),
+        _ => Vec::new(),
+    }
+}
+
+fn codec_mime_type(codec: &JsValue) -> String {
+    Reflect::get(codec, &"mimeType".into())
+        .ok()
+        .and_then(|mime_type| mime_type.as_string())
+        .unwrap_or_default()
+        .to_ascii_lowercase()
+}
+
+fn mime_subtype(mime_type: &str) -> String {
+    mime_type.rsplit('/').next().unwrap_or_default().to_ascii_lowercase()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SDP: &str = "v=0\r\n\
+o=- 1 2 IN IP4 127.0.0.1\r\n\
+s=-\r\n\
+t=0 0\r\n\
+m=audio 9 UDP/TLS/RTP/SAVPF 111 0\r\n\
+a=mid:0\r\n\
+a=rtpmap:111 opus/48000/2\r\n\
+a=rtpmap:0 PCMU/8000\r\n\
+m=video 9 UDP/TLS/RTP/SAVPF 96 97 98 99 120\r\n\
+a=mid:1\r\n\
+a=rtpmap:96 VP8/90000\r\n\
+a=rtpmap:97 rtx/90000\r\n\
+a=fmtp:97 apt=96\r\n\
+a=rtpmap:98 H264/90000\r\n\
+a=rtpmap:99 VP9/90000\r\n\
+m=video 9 UDP/TLS/RTP/SAVPF 96 98 99\r\n\
+a=mid:2\r\n\
+a=rtpmap:96 VP8/90000\r\n\
+a=rtpmap:98 H264/90000\r\n\
+a=rtpmap:99 VP9/90000\r\n\
+m=application 9 UDP/DTLS/SCTP webrtc-datachannel\r\n\
+a=mid:3\r\n\
+a=sctp-port:5000\r\n";
+
+    fn names(names: &[&str]) -> Vec<String> {
+        names.iter().map(|name| name.to_string()).collect()
+    }
+
+    fn media_lines(sdp: &str) -> Vec<&str> {
+        sdp.lines().map(|line| line.trim_end_matches('\r')).filter(|line| line.starts_with("m=")).collect()
+    }
+
+    #[test]
+    fn reorders_by_kind_and_keeps_crlf() {
+        let reordered = reorder_sdp(SDP, |kind, _| match kind {
+            TrackKind::Audio => Some(names(&["audio/pcmu"])),
+            TrackKind::Video => Some(names(&["video/h264", "video/vp8"])),
+        });
+        assert_eq!(
+            media_lines(&reordered),
+            vec![
+                "m=audio 9 UDP/TLS/RTP/SAVPF 0 111",
+                "m=video 9 UDP/TLS/RTP/SAVPF 98 96 97 99 120",
+                "m=video 9 UDP/TLS/RTP/SAVPF 98 96 99",
+                "m=application 9 UDP/DTLS/SCTP webrtc-datachannel",
+            ]
+        );
+        // 行末や m= 以外の行は変えない
+        assert!(reordered.ends_with("\r\n"));
+        assert_eq!(reordered.matches("\r\n").count(), SDP.matches("\r\n").count());
+        assert_eq!(reordered.replace(' ', "").len(), SDP.replace(' ', "").len());
+    }
+
+    #[test]
+    fn accepts_lf_line_endings() {
+        let sdp = SDP.replace("\r\n", "\n");
+        let reordered = reorder_sdp(&sdp, |kind, _| (kind == TrackKind::Video).then(|| names(&["video/vp9"])));
+        assert!(!reordered.contains('\r'));
+        assert!(reordered.ends_with('\n'));
+        assert_eq!(
+            media_lines(&reordered),
+            vec![
+                "m=audio 9 UDP/TLS/RTP/SAVPF 111 0",
+                "m=video 9 UDP/TLS/RTP/SAVPF 99 96 97 98 120",
+                "m=video 9 UDP/TLS/RTP/SAVPF 99 96 98",
+                "m=application 9 UDP/DTLS/SCTP webrtc-datachannel",
+            ]
+        );
+    }
+
+    #[test]
+    fn mid_override_applies_to_one_section() {
+        let reordered = reorder_sdp(SDP, |kind, mid| match (kind, mid) {
+            (TrackKind::Video, Some("2")) => Some(names(&["video/vp9"])),
+            (TrackKind::Video, _) => Some(names(&["video/h264"])),
+            _ => None,
+        });
+        let lines = media_lines(&reordered);
+        assert_eq!(lines[1], "m=video 9 UDP/TLS/RTP/SAVPF 98 96 97 99 120");
+        assert_eq!(lines[2], "m=video 9 UDP/TLS/RTP/SAVPF 99 96 98");
+    }
+
+    #[test]
+    fn unknown_payload_types_stay_last_in_order() {
+        // 120 は rtpmap が無く、97 (rtx) は優先順に含まれない
+        let reordered = reorder_sdp(SDP, |kind, mid| (kind == TrackKind::Video && mid == Some("1")).then(|| names(&["video/vp9", "video/h264"])));
+        assert_eq!(media_lines(&reordered)[1], "m=video 9 UDP/TLS/RTP/SAVPF 99 98 96 97 120");
+    }
+
+    #[test]
+    fn no_preference_leaves_sdp_unchanged() {
+        assert_eq!(reorder_sdp(SDP, |_, _| None), SDP);
+        assert_eq!(reorder_sdp(SDP, |_, _| Some(Vec::new())), SDP);
+    }
+
+    #[test]
+    fn application_section_is_untouched() {
+        let sdp = "v=0\r\nm=application 9 UDP/DTLS/SCTP webrtc-datachannel\r\na=mid:0\r\n";
+        let asked = std::cell::Cell::new(false);
+        let reordered = reorder_sdp(sdp, |_, _| {
+            asked.set(true);
+            Some(names(&["video/vp8"]))
+        });
+        assert_eq!(reordered, sdp);
+        assert!(!asked.get());
+    }
+}
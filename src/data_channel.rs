@@ -0,0 +1,286 @@
+use std::cell::RefCell;
+use std::rc::Rc;
+use serde::Deserialize;
+use wasm_bindgen::prelude::*;
+use web_sys::{MessageEvent, RtcDataChannel, RtcDataChannelInit, RtcDataChannelState, RtcDataChannelType};
+
+// create_data_channel に渡すオプション (RTCDataChannelInit と同じ名前)
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default, rename_all = "camelCase")]
+pub struct DataChannelOptions {
+    pub ordered: Option<bool>,
+    pub max_packet_life_time: Option<u16>,
+    pub max_retransmits: Option<u16>,
+    pub protocol: Option<String>,
+    pub negotiated: Option<bool>,
+    pub id: Option<u16>,
+}
+
+impl DataChannelOptions {
+    pub fn from_js(value: JsValue) -> Result<DataChannelOptions, JsValue> {
+        let options: DataChannelOptions = if value.is_undefined() || value.is_null() {
+            DataChannelOptions::default()
+        } else {
+            serde_wasm_bindgen::from_value(value)?
+        };
+        options.validate().map_err(|e| JsValue::from_str(&e))?;
+        Ok(options)
+    }
+
+    pub fn validate(&self) -> Result<(), String> {
+        if self.max_packet_life_time.is_some() && self.max_retransmits.is_some() {
+            return Err("maxPacketLifeTime and maxRetransmits cannot both be set".to_string());
+        }
+        if self.negotiated == Some(true) && self.id.is_none() {
+            return Err("negotiated channels need an id".to_string());
+        }
+        Ok(())
+    }
+
+    pub fn to_init(&self) -> RtcDataChannelInit {
+        let init = RtcDataChannelInit::new();
+        if let Some(ordered) = self.ordered {
+            init.set_ordered(ordered);
+        }
+        if let Some(life_time) = self.max_packet_life_time {
+            init.set_max_packet_life_time(life_time);
+        }
+        if let Some(retransmits) = self.max_retransmits {
+            init.set_max_retransmits(retransmits);
+        }
+        if let Some(protocol) = &self.protocol {
+            init.set_protocol(protocol);
+        }
+        if let Some(negotiated) = self.negotiated {
+            init.set_negotiated(negotiated);
+        }
+        if let Some(id) = self.id {
+            init.set_id(id);
+        }
+        init
+    }
+}
+
+// RTCDataChannel のラッパー
+// テキストは on_message、バイナリは on_binary (Uint8Array) で受け取る
+#[wasm_bindgen]
+#[derive(Clone)]
+pub struct DataChannel {
+    channel: RtcDataChannel,
+    state: Rc<ChannelState>,
+}
+
+// Rust 側 (ファイル転送など) で受け取るチャネルのイベント
+pub(crate) enum ChannelEvent {
+    Open,
+    Text(String),
+    Binary(js_sys::Uint8Array),
+    Close,
+}
+
+pub(crate) type ChannelHandler = Rc<dyn Fn(ChannelEvent)>;
+
+#[derive(Default)]
+struct ChannelCallbacks {
+    handler: Option<ChannelHandler>,
+    on_open: Option<js_sys::Function>,
+    on_message: Option<js_sys::Function>,
+    on_binary: Option<js_sys::Function>,
+    on_close: Option<js_sys::Function>,
+    on_error: Option<js_sys::Function>,
+}
+
+struct ChannelState {
+    channel: RtcDataChannel,
+    callbacks: Rc<RefCell<ChannelCallbacks>>,
+    _closures: ChannelClosures,
+}
+
+struct ChannelClosures {
+    on_open: Closure<dyn Fn(JsValue)>,
+    on_message: Closure<dyn Fn(MessageEvent)>,
+    on_close: Closure<dyn Fn(JsValue)>,
+    on_error: Closure<dyn Fn(JsValue)>,
+}
+
+impl ChannelClosures {
+    fn attach(&self, channel: &RtcDataChannel) {
+        channel.set_onopen(Some(self.on_open.as_ref().unchecked_ref()));
+        channel.set_onmessage(Some(self.on_message.as_ref().unchecked_ref()));
+        channel.set_onclose(Some(self.on_close.as_ref().unchecked_ref()));
+        channel.set_onerror(Some(self.on_error.as_ref().unchecked_ref()));
+    }
+}
+
+impl Drop for ChannelState {
+    // ラッパーが破棄されたらハンドラーを外す (解放済みのクロージャが呼ばれないように)
+    fn drop(&mut self) {
+        self.channel.set_onopen(None);
+        self.channel.set_onmessage(None);
+        self.channel.set_onclose(None);
+        self.channel.set_onerror(None);
+    }
+}
+
+#[wasm_bindgen]
+impl DataChannel {
+    pub fn label(&self) -> String {
+        self.channel.label()
+    }
+
+    // "connecting" / "open" / "closing" / "closed"
+    pub fn ready_state(&self) -> String {
+        match self.channel.ready_state() {
+            RtcDataChannelState::Connecting => "connecting",
+            RtcDataChannelState::Open => "open",
+            RtcDataChannelState::Closing => "closing",
+            _ => "closed",
+        }
+        .to_string()
+    }
+
+    pub fn is_open(&self) -> bool {
+        self.channel.ready_state() == RtcDataChannelState::Open
+    }
+
+    // 送信待ちのバイト数
+    pub fn buffered_amount(&self) -> u32 {
+        self.channel.buffered_amount()
+    }
+
+    pub fn send_text(&self, text: &str) -> Result<(), JsValue> {
+        self.channel.send_with_str(text)
+    }
+
+    pub fn send_bytes(&self, data: &[u8]) -> Result<(), JsValue> {
+        self.channel.send_with_u8_array(data)
+    }
+
+    pub fn close(&self) {
+        self.channel.close();
+    }
+
+    pub fn on_open(&self, callback: js_sys::Function) -> Result<(), JsValue> {
+        self.state.callbacks.borrow_mut().on_open = Some(callback);
+        Ok(())
+    }
+
+    // テキストメッセージを受信したときに (string) で呼ばれる
+    pub fn on_message(&self, callback: js_sys::Function) -> Result<(), JsValue> {
+        self.state.callbacks.borrow_mut().on_message = Some(callback);
+        Ok(())
+    }
+
+    // バイナリメッセージを受信したときに (Uint8Array) で呼ばれる
+    pub fn on_binary(&self, callback: js_sys::Function) -> Result<(), JsValue> {
+        self.state.callbacks.borrow_mut().on_binary = Some(callback);
+        Ok(())
+    }
+
+    pub fn on_close(&self, callback: js_sys::Function) -> Result<(), JsValue> {
+        self.state.callbacks.borrow_mut().on_close = Some(callback);
+        Ok(())
+    }
+
+    pub fn on_error(&self, callback: js_sys::Function) -> Result<(), JsValue> {
+        self.state.callbacks.borrow_mut().on_error = Some(callback);
+        Ok(())
+    }
+}
+
+impl DataChannel {
+    pub(crate) fn send_buffer(&self, data: &js_sys::Uint8Array) -> Result<(), JsValue> {
+        self.channel.send_with_array_buffer_view(data)
+    }
+
+    pub(crate) fn set_handler(&self, handler: Option<ChannelHandler>) {
+        self.state.callbacks.borrow_mut().handler = handler;
+    }
+
+    // 送信バッファが threshold 以下になるかチャネルが閉じるまで待つ
+    pub(crate) async fn wait_for_buffered_below(&self, threshold: u32) {
+        if self.channel.buffered_amount() <= threshold || !self.is_open() {
+            return;
+        }
+        self.channel.set_buffered_amount_low_threshold(threshold);
+        let channel = self.channel.clone();
+        let promise = js_sys::Promise::new(&mut |resolve, _reject| {
+            channel.set_onbufferedamountlow(Some(&resolve));
+        });
+        let _ = wasm_bindgen_futures::JsFuture::from(promise).await;
+        self.channel.set_onbufferedamountlow(None);
+    }
+
+    pub(crate) fn new(channel: RtcDataChannel) -> DataChannel {
+        channel.set_binary_type(RtcDataChannelType::Arraybuffer);
+        let callbacks = Rc::new(RefCell::new(ChannelCallbacks::default()));
+
+        let open_callbacks = callbacks.clone();
+        let on_open = Closure::wrap(Box::new(move |_event: JsValue| {
+            let handler = open_callbacks.borrow().handler.clone();
+            if let Some(handler) = handler {
+                handler(ChannelEvent::Open);
+            }
+            let callback = open_callbacks.borrow().on_open.clone();
+            if let Some(callback) = callback {
+                let _ = callback.call0(&JsValue::NULL);
+            }
+        }) as Box<dyn Fn(JsValue)>);
+
+        let message_callbacks = callbacks.clone();
+        let on_message = Closure::wrap(Box::new(move |event: MessageEvent| {
+            let data = event.data();
+            let handler = message_callbacks.borrow().handler.clone();
+            if let Some(buffer) = data.dyn_ref::<js_sys::ArrayBuffer>() {
+                if let Some(handler) = handler {
+                    handler(ChannelEvent::Binary(js_sys::Uint8Array::new(buffer)));
+                }
+                let callback = message_callbacks.borrow().on_binary.clone();
+                if let Some(callback) = callback {
+                    let _ = callback.call1(&JsValue::NULL, &js_sys::Uint8Array::new(buffer));
+                }
+                return;
+            }
+            if let (Some(handler), Some(text)) = (handler, data.as_string()) {
+                handler(ChannelEvent::Text(text));
+            }
+            let callback = message_callbacks.borrow().on_message.clone();
+            if let Some(callback) = callback {
+                let _ = callback.call1(&JsValue::NULL, &data);
+            }
+        }) as Box<dyn Fn(MessageEvent)>);
+
+        let close_callbacks = callbacks.clone();
+        let close_channel = channel.clone();
+        let on_close = Closure::wrap(Box::new(move |_event: JsValue| {
+            // wait_for_buffered_below で待っている送信側を起こす
+            if let Some(waiting) = close_channel.onbufferedamountlow() {
+                close_channel.set_onbufferedamountlow(None);
+                let _ = waiting.call0(&JsValue::NULL);
+            }
+            let handler = close_callbacks.borrow().handler.clone();
+            if let Some(handler) = handler {
+                handler(ChannelEvent::Close);
+            }
+            let callback = close_callbacks.borrow().on_close.clone();
+            if let Some(callback) = callback {
+                let _ = callback.call0(&JsValue::NULL);
+            }
+        }) as Box<dyn Fn(JsValue)>);
+
+        let error_callbacks = callbacks.clone();
+        let on_error = Closure::wrap(Box::new(move |event: JsValue| {
+            web_sys::console::error_1(&event);
+            let callback = error_callbacks.borrow().on_error.clone();
+            if let Some(callback) = callback {
+                let _ = callback.call1(&JsValue::NULL, &event);
+            }
+        }) as Box<dyn Fn(JsValue)>);
+
+        let closures = ChannelClosures { on_open, on_message, on_close, on_error };
+        closures.attach(&channel);
+
+        let state = Rc::new(ChannelState { channel: channel.clone(), callbacks, _closures: closures });
+        DataChannel { channel, state }
+    }
+}
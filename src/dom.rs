@@ -0,0 +1,45 @@
+use web_sys::{Element, HtmlVideoElement, MediaStream};
+use crate::status::ConnectionStatus;
+
+// 任意で使える DOM への表示ヘルパー
+// ライブラリ本体はイベントを発火するだけで、要素が指定された場合のみここで表示する
+#[derive(Default)]
+pub(crate) struct DomBinding {
+    pub local_video: Option<HtmlVideoElement>,
+    pub remote_video: Option<HtmlVideoElement>,
+    pub status: Option<Element>,
+}
+
+impl DomBinding {
+    pub fn show_local_stream(&self, stream: &MediaStream) {
+        if let Some(video) = &self.local_video {
+            video.set_src_object(Some(stream));
+        }
+    }
+
+    pub fn show_remote_stream(&self, stream: &MediaStream) {
+        if let Some(video) = &self.remote_video {
+            video.set_src_object(Some(stream));
+        }
+    }
+
+    // 相手が通話を終えたとき、最後のフレームが残らないように外す
+    pub fn clear_remote_stream(&self) {
+        if let Some(video) = &self.remote_video {
+            video.set_src_object(None);
+        }
+    }
+
+    // 通話終了時に映像を外す
+    pub fn clear_streams(&self) {
+        for video in [&self.local_video, &self.remote_video].into_iter().flatten() {
+            video.set_src_object(None);
+        }
+    }
+
+    pub fn show_status(&self, status: ConnectionStatus) {
+        if let Some(element) = &self.status {
+            element.set_text_content(Some(status.description()));
+        }
+    }
+}
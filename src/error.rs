@@ -0,0 +1,88 @@
+use std::fmt;
+use js_sys::Reflect;
+use wasm_bindgen::prelude::*;
+use crate::webrtc_peer_connection::error_message;
+
+// ライブラリが返す・on_error に渡すエラー
+// JS には code プロパティ ("transport" など) 付きの Error として渡る
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ClientError {
+    // WebSocket やピア接続のトランスポート
+    Transport(String),
+    // シグナリングメッセージを送れなかった
+    Signaling(String),
+    // offer / answer / ICE candidate の処理に失敗した
+    Negotiation(String),
+    // キャプチャやトラックの操作に失敗した
+    Media(String),
+    // カメラ・マイク・画面の利用が許可されなかった
+    Permission(String),
+    // 受信したメッセージや引数が不正
+    Protocol(String),
+}
+
+impl ClientError {
+    // JS の Error の code プロパティ。変更しないこと
+    pub fn code(&self) -> &'static str {
+        match self {
+            ClientError::Transport(_) => "transport",
+            ClientError::Signaling(_) => "signaling",
+            ClientError::Negotiation(_) => "negotiation",
+            ClientError::Media(_) => "media",
+            ClientError::Permission(_) => "permission",
+            ClientError::Protocol(_) => "protocol",
+        }
+    }
+
+    pub fn message(&self) -> &str {
+        match self {
+            ClientError::Transport(message)
+            | ClientError::Signaling(message)
+            | ClientError::Negotiation(message)
+            | ClientError::Media(message)
+            | ClientError::Permission(message)
+            | ClientError::Protocol(message) => message,
+        }
+    }
+
+    // JS の例外から作る (map_err に渡せるように値で受け取る)
+    pub fn transport(error: JsValue) -> ClientError {
+        ClientError::Transport(error_message(&error))
+    }
+
+    pub fn signaling(error: JsValue) -> ClientError {
+        ClientError::Signaling(error_message(&error))
+    }
+
+    pub fn negotiation(error: JsValue) -> ClientError {
+        ClientError::Negotiation(error_message(&error))
+    }
+
+    pub fn protocol(error: JsValue) -> ClientError {
+        ClientError::Protocol(error_message(&error))
+    }
+
+    // getUserMedia / getDisplayMedia の例外。拒否された場合は Permission にする
+    pub fn media(error: JsValue) -> ClientError {
+        let name = Reflect::get(&error, &"name".into()).ok().and_then(|name| name.as_string());
+        match name.as_deref() {
+            Some("NotAllowedError") | Some("SecurityError") => ClientError::Permission(error_message(&error)),
+            _ => ClientError::Media(error_message(&error)),
+        }
+    }
+}
+
+impl fmt::Display for ClientError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} error: {}", self.code(), self.message())
+    }
+}
+
+impl From<ClientError> for JsValue {
+    fn from(error: ClientError) -> JsValue {
+        let js_error = js_sys::Error::new(error.message());
+        js_error.set_name("ClientError");
+        let _ = Reflect::set(&js_error, &"code".into(), &error.code().into());
+        js_error.into()
+    }
+}
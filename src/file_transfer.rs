@@ -0,0 +1,516 @@
+use std::cell::{Cell, RefCell};
+use std::collections::HashMap;
+use std::rc::Rc;
+use serde::{Deserialize, Serialize};
+use wasm_bindgen::prelude::*;
+use wasm_bindgen_futures::{spawn_local, JsFuture};
+use web_sys::{console, Blob, BlobPropertyBag};
+use crate::data_channel::{ChannelEvent, DataChannel};
+use crate::error::ClientError;
+use crate::sha256::Sha256;
+use crate::timer::Timeout;
+use crate::{set_timeout, ClientInner};
+
+// ファイル転送用のデータチャネルは "file:<転送ID>" というラベルで作る
+pub(crate) const LABEL_PREFIX: &str = "file:";
+// SCTP で分割されずにどのブラウザにも届くサイズ
+const CHUNK_SIZE: u64 = 16 * 1024;
+// 送信バッファがこれを超えたら LOW_WATER_MARK まで減るのを待つ
+const HIGH_WATER_MARK: u32 = 1024 * 1024;
+const LOW_WATER_MARK: u32 = 256 * 1024;
+// ハッシュ計算で一度に読む量
+const HASH_READ_SIZE: u64 = 1024 * 1024;
+// 切断後に自動で再開を試みる間隔と回数
+const RESUME_DELAY_MS: u32 = 3000;
+const MAX_RESUME_ATTEMPTS: u32 = 5;
+// 途切れた受信を再開を待って保持する時間 (送信側の再開の試行より十分長く)
+const INCOMING_EXPIRY_MS: u32 = 60_000;
+
+// チャネル上の制御メッセージ (テキスト)。ファイルの中身はバイナリで送る
+// offer -> accept(offset) -> チャンク... -> end -> done(ok)
+// 再開時は同じ ID で offer し直し、受信側は受信済みのバイト数を offset で返す
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "kebab-case", rename_all_fields = "camelCase")]
+enum FileMessage {
+    Offer { id: String, name: String, size: u64, mime_type: String, sha256: String },
+    Accept { id: String, offset: u64 },
+    End { id: String },
+    Done { id: String, ok: bool },
+    Cancel { id: String },
+}
+
+// コールバックに渡すファイルの情報
+#[derive(Debug, Clone, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub(crate) struct FileInfo {
+    pub id: String,
+    pub name: String,
+    pub size: u64,
+    pub mime_type: String,
+    pub peer_id: Option<String>,
+}
+
+#[derive(Default)]
+pub(crate) struct FileTransfers {
+    outgoing: HashMap<String, Rc<Outgoing>>,
+    incoming: HashMap<String, Rc<Incoming>>,
+}
+
+struct Outgoing {
+    info: FileInfo,
+    // ファイルの中身は JS 側の Blob のまま持ち、送るチャンクだけを読む
+    file: Blob,
+    sha256: RefCell<Option<String>>,
+    channel: RefCell<Option<DataChannel>>,
+    sending: Cell<bool>,
+    resume_attempts: Cell<u32>,
+}
+
+struct Incoming {
+    info: FileInfo,
+    sha256: String,
+    // 受信したチャンク (JS のメモリ上の Uint8Array)。完了時に Blob にまとめる
+    parts: js_sys::Array,
+    received: Cell<u64>,
+    hasher: RefCell<Sha256>,
+    channel: RefCell<Option<DataChannel>>,
+    // 途切れてから再開されなければ破棄するタイマー
+    expiry: RefCell<Option<Timeout>>,
+}
+
+impl ClientInner {
+    // ファイルの送信を開始し、転送IDを返す
+    pub(crate) fn send_file(self: &Rc<Self>, file: Blob, name: String, peer_id: Option<String>) -> Result<String, ClientError> {
+        let Some(connection) = self.connection_for(peer_id.as_deref()) else {
+            return Err(ClientError::Protocol(format!("unknown peer: {}", peer_id.unwrap_or_default())));
+        };
+        // 相手が決まっていない1対1の接続ではチャネルが開かないので受け付けない
+        if !connection.has_remote() {
+            return Err(no_remote());
+        }
+        let info = FileInfo {
+            id: generate_transfer_id(),
+            name,
+            size: file.size() as u64,
+            mime_type: file.type_(),
+            peer_id,
+        };
+        let id = info.id.clone();
+        let outgoing = Rc::new(Outgoing {
+            info,
+            file,
+            sha256: RefCell::new(None),
+            channel: RefCell::new(None),
+            sending: Cell::new(false),
+            resume_attempts: Cell::new(0),
+        });
+        self.file_transfers.borrow_mut().outgoing.insert(id.clone(), outgoing.clone());
+
+        let inner = self.clone();
+        spawn_local(async move {
+            // 受信側で照合するハッシュを先に計算する (ファイル全体はメモリに載せない)
+            match hash_blob(&outgoing.file).await {
+                Ok(hash) => {
+                    outgoing.sha256.replace(Some(hash));
+                    if let Err(e) = inner.open_outgoing(&outgoing) {
+                        inner.fail_outgoing(&outgoing.info.id, e);
+                    }
+                }
+                Err(e) => inner.fail_outgoing(&outgoing.info.id, ClientError::transport(e)),
+            }
+        });
+        Ok(id)
+    }
+
+    // 送信を中止する。相手にも cancel を送る
+    pub(crate) fn cancel_file(&self, id: &str) -> Result<(), ClientError> {
+        let channel = {
+            let mut transfers = self.file_transfers.borrow_mut();
+            if let Some(outgoing) = transfers.outgoing.remove(id) {
+                outgoing.channel.take()
+            } else if let Some(incoming) = transfers.incoming.remove(id) {
+                incoming.channel.take()
+            } else {
+                return Err(ClientError::Protocol(format!("unknown file transfer: {}", id)));
+            }
+        };
+        if let Some(channel) = channel {
+            if channel.is_open() {
+                send_control(&channel, &FileMessage::Cancel { id: id.to_string() });
+            }
+            channel.set_handler(None);
+            channel.close();
+        }
+        Ok(())
+    }
+
+    // 進行中の転送を全て中止する (hang_up 用)
+    pub(crate) fn cancel_all_files(&self) {
+        let ids: Vec<String> = {
+            let transfers = self.file_transfers.borrow();
+            transfers.outgoing.keys().chain(transfers.incoming.keys()).cloned().collect()
+        };
+        for id in ids {
+            let _ = self.cancel_file(&id);
+        }
+    }
+
+    // 途切れた送信を再開する (自動再開を待たずに呼ぶ場合)
+    pub(crate) fn resume_file(self: &Rc<Self>, id: &str) -> Result<(), ClientError> {
+        let Some(outgoing) = self.file_transfers.borrow().outgoing.get(id).cloned() else {
+            return Err(ClientError::Protocol(format!("unknown outgoing file transfer: {}", id)));
+        };
+        if outgoing.channel.borrow().as_ref().is_some_and(DataChannel::is_open) {
+            return Ok(());
+        }
+        if outgoing.sha256.borrow().is_none() {
+            return Err(ClientError::Protocol("file hash is still being computed".to_string()));
+        }
+        self.open_outgoing(&outgoing)
+    }
+
+    fn open_outgoing(self: &Rc<Self>, outgoing: &Rc<Outgoing>) -> Result<(), ClientError> {
+        let Some(connection) = self.connection_for(outgoing.info.peer_id.as_deref()) else {
+            return Err(ClientError::Protocol("peer connection is gone".to_string()));
+        };
+        if !connection.has_remote() {
+            return Err(no_remote());
+        }
+        let id = outgoing.info.id.clone();
+        let channel = connection
+            .create_data_channel(&format!("{}{}", LABEL_PREFIX, id), JsValue::NULL)
+            .map_err(ClientError::transport)?;
+
+        let weak = Rc::downgrade(self);
+        let handler_channel = channel.clone();
+        channel.set_handler(Some(Rc::new(move |event: ChannelEvent| {
+            let Some(inner) = weak.upgrade() else {
+                return;
+            };
+            let Some(outgoing) = inner.file_transfers.borrow().outgoing.get(&id).cloned() else {
+                return;
+            };
+            match event {
+                ChannelEvent::Open => {
+                    let Some(sha256) = outgoing.sha256.borrow().clone() else {
+                        return;
+                    };
+                    let info = &outgoing.info;
+                    send_control(&handler_channel, &FileMessage::Offer {
+                        id: info.id.clone(),
+                        name: info.name.clone(),
+                        size: info.size,
+                        mime_type: info.mime_type.clone(),
+                        sha256,
+                    });
+                }
+                ChannelEvent::Text(text) => match serde_json::from_str::<FileMessage>(&text) {
+                    Ok(FileMessage::Accept { offset, .. }) => {
+                        outgoing.resume_attempts.set(0);
+                        spawn_local(inner.clone().send_chunks(outgoing, handler_channel.clone(), offset));
+                    }
+                    Ok(FileMessage::Done { ok: true, .. }) => {
+                        inner.file_transfers.borrow_mut().outgoing.remove(&id);
+                        handler_channel.set_handler(None);
+                        handler_channel.close();
+                        let callback = inner.callbacks.borrow().on_file_sent.clone();
+                        if let Some(callback) = callback {
+                            let _ = callback.call1(&JsValue::NULL, &to_js(&outgoing.info));
+                        }
+                    }
+                    Ok(FileMessage::Done { ok: false, .. }) => {
+                        handler_channel.set_handler(None);
+                        handler_channel.close();
+                        inner.fail_outgoing(&id, ClientError::Protocol("receiver reported a checksum mismatch".to_string()));
+                    }
+                    Ok(FileMessage::Cancel { .. }) => {
+                        handler_channel.set_handler(None);
+                        inner.fail_outgoing(&id, ClientError::Protocol("cancelled by peer".to_string()));
+                    }
+                    Ok(message) => console::warn_1(&format!("Unexpected file message: {:?}", message).into()),
+                    Err(e) => console::error_1(&format!("Malformed file message: {}", e).into()),
+                },
+                ChannelEvent::Binary(_) => {}
+                ChannelEvent::Close => {
+                    handler_channel.set_handler(None);
+                    inner.interrupt_outgoing(&outgoing);
+                }
+            }
+        })));
+        outgoing.channel.replace(Some(channel));
+        Ok(())
+    }
+
+    async fn send_chunks(self: Rc<Self>, outgoing: Rc<Outgoing>, channel: DataChannel, offset: u64) {
+        if outgoing.sending.replace(true) {
+            return;
+        }
+        let size = outgoing.info.size;
+        let mut position = offset.min(size);
+        while position < size {
+            if channel.buffered_amount() > HIGH_WATER_MARK {
+                channel.wait_for_buffered_below(LOW_WATER_MARK).await;
+            }
+            // 中止またはチャネルが閉じた (再開は Close イベント側で扱う)
+            if !channel.is_open() || !self.file_transfers.borrow().outgoing.contains_key(&outgoing.info.id) {
+                outgoing.sending.set(false);
+                return;
+            }
+            let end = (position + CHUNK_SIZE).min(size);
+            let sent = match read_slice(&outgoing.file, position, end).await {
+                Ok(chunk) => channel.send_buffer(&chunk),
+                Err(e) => Err(e),
+            };
+            if let Err(e) = sent {
+                outgoing.sending.set(false);
+                if channel.is_open() {
+                    self.fail_outgoing(&outgoing.info.id, ClientError::transport(e));
+                }
+                return;
+            }
+            position = end;
+            self.emit_file_progress(&outgoing.info, position, "send");
+        }
+        outgoing.sending.set(false);
+        send_control(&channel, &FileMessage::End { id: outgoing.info.id.clone() });
+    }
+
+    // チャネルが途中で閉じた。少し待ってから同じ ID で再開を試みる
+    fn interrupt_outgoing(self: &Rc<Self>, outgoing: &Rc<Outgoing>) {
+        outgoing.channel.take();
+        let attempt = outgoing.resume_attempts.get() + 1;
+        let resumable = attempt <= MAX_RESUME_ATTEMPTS;
+        self.emit_file_error(&outgoing.info, "transfer interrupted", resumable);
+        if !resumable {
+            return;
+        }
+        outgoing.resume_attempts.set(attempt);
+
+        let weak = Rc::downgrade(self);
+        let id = outgoing.info.id.clone();
+        let resume = Closure::once_into_js(move || {
+            let Some(inner) = weak.upgrade() else {
+                return;
+            };
+            let Some(outgoing) = inner.file_transfers.borrow().outgoing.get(&id).cloned() else {
+                return;
+            };
+            console::log_1(&format!("Resuming file transfer {} (attempt {})", id, attempt).into());
+            if let Err(e) = inner.open_outgoing(&outgoing) {
+                console::error_1(&e.to_string().into());
+                inner.interrupt_outgoing(&outgoing);
+            }
+        });
+        if let Err(e) = set_timeout(&resume, RESUME_DELAY_MS) {
+            console::error_1(&e);
+        }
+    }
+
+    // 送信を諦める。on_file_error に加えて on_error にも通知する
+    fn fail_outgoing(&self, id: &str, error: ClientError) {
+        let Some(outgoing) = self.file_transfers.borrow_mut().outgoing.remove(id) else {
+            return;
+        };
+        if let Some(channel) = outgoing.channel.take() {
+            channel.set_handler(None);
+            channel.close();
+        }
+        self.emit_file_error(&outgoing.info, error.message(), false);
+        self.report_error(error);
+    }
+
+    // 相手が作った "file:" チャネルを受け取る
+    pub(crate) fn accept_file_channel(self: &Rc<Self>, channel: DataChannel, peer_id: Option<String>) {
+        let label = channel.label();
+        let id = label.trim_start_matches(LABEL_PREFIX).to_string();
+        let weak = Rc::downgrade(self);
+        let handler_channel = channel.clone();
+        channel.set_handler(Some(Rc::new(move |event: ChannelEvent| {
+            let Some(inner) = weak.upgrade() else {
+                return;
+            };
+            match event {
+                ChannelEvent::Open => {}
+                ChannelEvent::Text(text) => match serde_json::from_str::<FileMessage>(&text) {
+                    Ok(FileMessage::Offer { id: offer_id, name, size, mime_type, sha256 }) if offer_id == id => {
+                        let info = FileInfo { id: offer_id, name, size, mime_type, peer_id: peer_id.clone() };
+                        inner.receive_offer(info, sha256, &handler_channel);
+                    }
+                    Ok(FileMessage::End { .. }) => inner.finish_incoming(&id, &handler_channel),
+                    Ok(FileMessage::Cancel { .. }) => {
+                        handler_channel.set_handler(None);
+                        if let Some(incoming) = inner.file_transfers.borrow_mut().incoming.remove(&id) {
+                            inner.emit_file_error(&incoming.info, "cancelled by peer", false);
+                        }
+                    }
+                    Ok(message) => console::warn_1(&format!("Unexpected file message: {:?}", message).into()),
+                    Err(e) => console::error_1(&format!("Malformed file message: {}", e).into()),
+                },
+                ChannelEvent::Binary(chunk) => inner.receive_chunk(&id, chunk),
+                ChannelEvent::Close => {
+                    handler_channel.set_handler(None);
+                    // 受信済みの分は残しておき、送信側からの再開を待つ
+                    let incoming = inner.file_transfers.borrow().incoming.get(&id).cloned();
+                    if let Some(incoming) = incoming {
+                        incoming.channel.take();
+                        inner.expire_incoming_later(&incoming);
+                        inner.emit_file_error(&incoming.info, "transfer interrupted", true);
+                    }
+                }
+            }
+        })));
+    }
+
+    fn receive_offer(&self, info: FileInfo, sha256: String, channel: &DataChannel) {
+        let existing = self.file_transfers.borrow().incoming.get(&info.id).cloned();
+        // 同じファイルの再開なら受信済みのバイト数から続ける
+        let incoming = match existing {
+            Some(incoming) if incoming.sha256 == sha256 && incoming.info.size == info.size => incoming,
+            _ => {
+                let incoming = Rc::new(Incoming {
+                    info,
+                    sha256,
+                    parts: js_sys::Array::new(),
+                    received: Cell::new(0),
+                    hasher: RefCell::new(Sha256::default()),
+                    channel: RefCell::new(None),
+                    expiry: RefCell::new(None),
+                });
+                self.file_transfers.borrow_mut().incoming.insert(incoming.info.id.clone(), incoming.clone());
+                incoming
+            }
+        };
+        incoming.expiry.take();
+        incoming.channel.replace(Some(channel.clone()));
+        send_control(channel, &FileMessage::Accept { id: incoming.info.id.clone(), offset: incoming.received.get() });
+    }
+
+    // 送信側が再開を諦めた場合に受信済みのチャンクを持ち続けないようにする
+    fn expire_incoming_later(self: &Rc<Self>, incoming: &Rc<Incoming>) {
+        let weak = Rc::downgrade(self);
+        let id = incoming.info.id.clone();
+        let timeout = Timeout::new(INCOMING_EXPIRY_MS, move || {
+            let Some(inner) = weak.upgrade() else {
+                return;
+            };
+            let expired = inner.file_transfers.borrow_mut().incoming.remove(&id);
+            if let Some(incoming) = expired {
+                console::log_1(&format!("File transfer {} expired without resuming", id).into());
+                inner.emit_file_error(&incoming.info, "transfer expired", false);
+                // 実行中のタイマーのクロージャを解放しないように後で破棄する
+                spawn_local(async move { drop(incoming) });
+            }
+        });
+        match timeout {
+            Ok(timeout) => {
+                incoming.expiry.replace(Some(timeout));
+            }
+            Err(e) => console::error_1(&e),
+        }
+    }
+
+    fn receive_chunk(&self, id: &str, chunk: js_sys::Uint8Array) {
+        let Some(incoming) = self.file_transfers.borrow().incoming.get(id).cloned() else {
+            return;
+        };
+        let received = incoming.received.get() + chunk.length() as u64;
+        if received > incoming.info.size {
+            if let Err(e) = self.cancel_file(id) {
+                console::error_1(&e.to_string().into());
+            }
+            self.emit_file_error(&incoming.info, "received more data than announced", false);
+            return;
+        }
+        incoming.hasher.borrow_mut().update(&chunk.to_vec());
+        incoming.parts.push(&chunk);
+        incoming.received.set(received);
+        self.emit_file_progress(&incoming.info, received, "receive");
+    }
+
+    fn finish_incoming(&self, id: &str, channel: &DataChannel) {
+        let Some(incoming) = self.file_transfers.borrow_mut().incoming.remove(id) else {
+            return;
+        };
+        let hash = incoming.hasher.borrow().clone().finish_hex();
+        let ok = incoming.received.get() == incoming.info.size && hash == incoming.sha256;
+        send_control(channel, &FileMessage::Done { id: id.to_string(), ok });
+        channel.set_handler(None);
+        if !ok {
+            self.emit_file_error(&incoming.info, "checksum mismatch", false);
+            return;
+        }
+
+        let options = BlobPropertyBag::new();
+        options.set_type(&incoming.info.mime_type);
+        match Blob::new_with_u8_array_sequence_and_options(&incoming.parts, &options) {
+            Ok(blob) => {
+                let callback = self.callbacks.borrow().on_file_received.clone();
+                if let Some(callback) = callback {
+                    let _ = callback.call2(&JsValue::NULL, &to_js(&incoming.info), &blob);
+                }
+            }
+            Err(e) => self.emit_file_error(&incoming.info, &error_text(&e), false),
+        }
+    }
+
+    fn emit_file_progress(&self, info: &FileInfo, bytes: u64, direction: &str) {
+        let callback = self.callbacks.borrow().on_file_progress.clone();
+        if let Some(callback) = callback {
+            let args = js_sys::Array::of3(&to_js(info), &JsValue::from(bytes as f64), &JsValue::from_str(direction));
+            let _ = callback.apply(&JsValue::NULL, &args);
+        }
+    }
+
+    fn emit_file_error(&self, info: &FileInfo, message: &str, resumable: bool) {
+        console::warn_1(&format!("File transfer {} ({}): {}", info.id, info.name, message).into());
+        let callback = self.callbacks.borrow().on_file_error.clone();
+        if let Some(callback) = callback {
+            let args = js_sys::Array::of3(&to_js(info), &JsValue::from_str(message), &JsValue::from(resumable));
+            let _ = callback.apply(&JsValue::NULL, &args);
+        }
+    }
+}
+
+fn send_control(channel: &DataChannel, message: &FileMessage) {
+    let result = serde_json::to_string(message)
+        .map_err(|e| JsValue::from_str(&e.to_string()))
+        .and_then(|text| channel.send_text(&text));
+    if let Err(e) = result {
+        console::error_1(&e);
+    }
+}
+
+async fn read_slice(file: &Blob, start: u64, end: u64) -> Result<js_sys::Uint8Array, JsValue> {
+    let slice = file.slice_with_f64_and_f64(start as f64, end as f64)?;
+    let buffer = JsFuture::from(slice.array_buffer()).await?;
+    Ok(js_sys::Uint8Array::new(&buffer))
+}
+
+async fn hash_blob(file: &Blob) -> Result<String, JsValue> {
+    let size = file.size() as u64;
+    let mut hasher = Sha256::default();
+    let mut position = 0;
+    while position < size {
+        let end = (position + HASH_READ_SIZE).min(size);
+        hasher.update(&read_slice(file, position, end).await?.to_vec());
+        position = end;
+    }
+    Ok(hasher.finish_hex())
+}
+
+fn to_js(info: &FileInfo) -> JsValue {
+    serde_wasm_bindgen::to_value(info).unwrap_or(JsValue::NULL)
+}
+
+fn error_text(error: &JsValue) -> String {
+    crate::webrtc_peer_connection::error_message(error)
+}
+
+fn no_remote() -> ClientError {
+    ClientError::Negotiation("the call is not negotiated yet; send an offer or wait for one before sending files".to_string())
+}
+
+fn generate_transfer_id() -> String {
+    let random = (js_sys::Math::random() * u32::MAX as f64) as u32;
+    format!("{:08x}{:08x}", random, js_sys::Date::now() as u64 as u32)
+}
@@ -0,0 +1,186 @@
+use serde::Deserialize;
+
+// アプリケーションレベルのハートビート設定
+// ブラウザの WebSocket では ping/pong フレームを扱えないため、通常のテキストメッセージで代用する
+//
+// - echoserver のように受信したものをそのまま返すサーバー: pong に ping と同じ文字列を指定する
+// - ChatServer: サーバーが {"type":"ping"} に送信元だけへ pong を返す (中継はしない)
+// - ping を中継するだけのリレー: replyToPing を有効にして他のクライアントに pong を返させる
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(default, rename_all = "camelCase")]
+pub struct HeartbeatOptions {
+    pub enabled: bool,
+    pub interval_ms: u32,
+    // ping 送信後この時間内に pong (または他の受信) が無ければ切断とみなす
+    pub timeout_ms: u32,
+    pub ping: String,
+    pub pong: String,
+    // 他のクライアントから ping を受信したら pong を返す
+    pub reply_to_ping: bool,
+    // pong 以外のメッセージ受信も生存確認として扱う
+    pub any_message_is_alive: bool,
+}
+
+impl Default for HeartbeatOptions {
+    fn default() -> Self {
+        HeartbeatOptions {
+            enabled: false,
+            interval_ms: 15_000,
+            timeout_ms: 5_000,
+            ping: r#"{"type":"ping"}"#.to_string(),
+            pong: r#"{"type":"pong"}"#.to_string(),
+            reply_to_ping: false,
+            any_message_is_alive: true,
+        }
+    }
+}
+
+impl HeartbeatOptions {
+    pub fn validate(&self) -> Result<(), String> {
+        if !self.enabled {
+            return Ok(());
+        }
+        if self.interval_ms == 0 || self.timeout_ms == 0 {
+            return Err("heartbeat.intervalMs and heartbeat.timeoutMs must be greater than 0".to_string());
+        }
+        if self.timeout_ms >= self.interval_ms {
+            return Err("heartbeat.timeoutMs must be less than intervalMs".to_string());
+        }
+        if self.ping.is_empty() || self.pong.is_empty() {
+            return Err("heartbeat.ping and heartbeat.pong must not be empty".to_string());
+        }
+        Ok(())
+    }
+}
+
+// 受信テキストの分類
+#[derive(Debug, PartialEq, Eq)]
+pub enum HeartbeatFrame {
+    Ping,
+    Pong,
+    Other,
+}
+
+// ハートビートの状態管理 (タイマーは呼び出し側で管理する)
+#[derive(Debug)]
+pub struct HeartbeatMonitor {
+    options: HeartbeatOptions,
+    next_ping_id: u32,
+    // pong 待ちの ping
+    awaiting: Option<u32>,
+}
+
+impl HeartbeatMonitor {
+    pub fn new(options: HeartbeatOptions) -> HeartbeatMonitor {
+        HeartbeatMonitor { options, next_ping_id: 0, awaiting: None }
+    }
+
+    pub fn options(&self) -> &HeartbeatOptions {
+        &self.options
+    }
+
+    pub fn classify(&self, text: &str) -> HeartbeatFrame {
+        // echo サーバー向けに ping と pong が同じ場合は pong として扱う
+        if text == self.options.pong {
+            HeartbeatFrame::Pong
+        } else if text == self.options.ping {
+            HeartbeatFrame::Ping
+        } else {
+            HeartbeatFrame::Other
+        }
+    }
+
+    // インターバルごとに呼ぶ。ping を送るべきならその ID を返す
+    pub fn on_tick(&mut self) -> Option<u32> {
+        if self.awaiting.is_some() {
+            return None;
+        }
+        let id = self.next_ping_id;
+        self.next_ping_id = self.next_ping_id.wrapping_add(1);
+        self.awaiting = Some(id);
+        Some(id)
+    }
+
+    // 受信があったときに呼ぶ
+    pub fn on_received(&mut self, frame: &HeartbeatFrame) {
+        if *frame == HeartbeatFrame::Pong || self.options.any_message_is_alive {
+            self.awaiting = None;
+        }
+    }
+
+    // ping のタイムアウト時に呼ぶ。まだ応答が無ければ true
+    pub fn is_lost(&self, ping_id: u32) -> bool {
+        self.awaiting == Some(ping_id)
+    }
+
+    pub fn reset(&mut self) {
+        self.awaiting = None;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn monitor(any_message_is_alive: bool) -> HeartbeatMonitor {
+        HeartbeatMonitor::new(HeartbeatOptions { enabled: true, any_message_is_alive, ..HeartbeatOptions::default() })
+    }
+
+    #[test]
+    fn pong_answers_the_pending_ping() {
+        let mut monitor = monitor(false);
+        assert_eq!(monitor.classify(r#"{"type":"pong"}"#), HeartbeatFrame::Pong);
+        assert_eq!(monitor.classify(r#"{"type":"ping"}"#), HeartbeatFrame::Ping);
+        let id = monitor.on_tick().unwrap();
+        monitor.on_received(&HeartbeatFrame::Pong);
+        assert!(!monitor.is_lost(id));
+        // 次の ping は別の ID になる
+        assert_ne!(monitor.on_tick(), Some(id));
+    }
+
+    #[test]
+    fn echo_server_ping_is_treated_as_pong() {
+        let options = HeartbeatOptions { ping: "hb".to_string(), pong: "hb".to_string(), ..HeartbeatOptions::default() };
+        assert_eq!(HeartbeatMonitor::new(options).classify("hb"), HeartbeatFrame::Pong);
+    }
+
+    #[test]
+    fn tick_does_not_rearm_while_waiting() {
+        let mut monitor = monitor(false);
+        let id = monitor.on_tick().unwrap();
+        assert_eq!(monitor.on_tick(), None);
+        assert!(monitor.is_lost(id));
+    }
+
+    #[test]
+    fn lost_after_timeout_without_reply() {
+        let mut monitor = monitor(false);
+        let id = monitor.on_tick().unwrap();
+        monitor.on_received(&HeartbeatFrame::Other);
+        monitor.on_received(&HeartbeatFrame::Ping);
+        assert!(monitor.is_lost(id));
+        monitor.reset();
+        assert!(!monitor.is_lost(id));
+    }
+
+    #[test]
+    fn any_message_counts_as_alive() {
+        let mut monitor = monitor(true);
+        let id = monitor.on_tick().unwrap();
+        monitor.on_received(&HeartbeatFrame::Other);
+        assert!(!monitor.is_lost(id));
+    }
+
+    #[test]
+    fn validate_rejects_bad_intervals() {
+        let enabled = HeartbeatOptions { enabled: true, ..HeartbeatOptions::default() };
+        assert!(enabled.validate().is_ok());
+        assert!(HeartbeatOptions { interval_ms: 0, ..enabled.clone() }.validate().is_err());
+        assert!(HeartbeatOptions { timeout_ms: 0, ..enabled.clone() }.validate().is_err());
+        assert!(HeartbeatOptions { interval_ms: 5_000, timeout_ms: 5_000, ..enabled.clone() }.validate().is_err());
+        assert!(HeartbeatOptions { interval_ms: 1_000, timeout_ms: 5_000, ..enabled.clone() }.validate().is_err());
+        assert!(HeartbeatOptions { pong: String::new(), ..enabled }.validate().is_err());
+        // 無効なら値は検査しない
+        assert!(HeartbeatOptions { interval_ms: 0, ..HeartbeatOptions::default() }.validate().is_ok());
+    }
+}
@@ -0,0 +1,134 @@
+use serde::{Deserialize, Serialize};
+
+// RTCIceServer と同じ形の ICE サーバー設定
+// JSからは { urls: "turn:turn.example.com:3478" | [...], username, credential } で指定する
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct IceServer {
+    #[serde(deserialize_with = "deserialize_urls")]
+    pub urls: Vec<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub username: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub credential: Option<String>,
+}
+
+// ICE サーバーの一覧。省略時は Google の STUN サーバーを使い、空配列なら ICE サーバー無し
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(transparent)]
+pub struct IceServerList(pub Vec<IceServer>);
+
+impl Default for IceServerList {
+    fn default() -> Self {
+        IceServerList(vec![IceServer {
+            urls: vec!["stun:stun.l.google.com:19302".to_string()],
+            username: None,
+            credential: None,
+        }])
+    }
+}
+
+impl IceServerList {
+    pub fn validate(&self) -> Result<(), String> {
+        self.0.iter().try_for_each(IceServer::validate)
+    }
+}
+
+impl IceServer {
+    pub fn validate(&self) -> Result<(), String> {
+        if self.urls.is_empty() {
+            return Err("iceServers[].urls must not be empty".to_string());
+        }
+        for url in &self.urls {
+            let scheme = url.split(':').next().unwrap_or_default();
+            match scheme {
+                "stun" | "stuns" => {}
+                // TURN (UDP / TCP / TLS) は認証情報が必須
+                "turn" | "turns" => {
+                    if self.username.as_deref().unwrap_or_default().is_empty()
+                        || self.credential.as_deref().unwrap_or_default().is_empty()
+                    {
+                        return Err(format!("TURN server {} requires username and credential", url));
+                    }
+                }
+                _ => return Err(format!("unsupported ICE server URL: {}", url)),
+            }
+            if url.len() <= scheme.len() + 1 {
+                return Err(format!("ICE server URL has no host: {}", url));
+            }
+        }
+        Ok(())
+    }
+}
+
+fn deserialize_urls<'de, D>(deserializer: D) -> Result<Vec<String>, D::Error>
+where
+    D: serde::Deserializer<'de>,
+{
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum Urls {
+        One(String),
+        Many(Vec<String>),
+    }
+    Ok(match Urls::deserialize(deserializer)? {
+        Urls::One(url) => vec![url],
+        Urls::Many(urls) => urls,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(json: &str) -> IceServer {
+        serde_json::from_str(json).unwrap()
+    }
+
+    fn server(url: &str, username: Option<&str>, credential: Option<&str>) -> IceServer {
+        IceServer {
+            urls: vec![url.to_string()],
+            username: username.map(str::to_string),
+            credential: credential.map(str::to_string),
+        }
+    }
+
+    #[test]
+    fn urls_accepts_string_or_array() {
+        assert_eq!(parse(r#"{"urls":"stun:a.example.com"}"#).urls, vec!["stun:a.example.com"]);
+        assert_eq!(
+            parse(r#"{"urls":["stun:a.example.com","stuns:b.example.com"]}"#).urls,
+            vec!["stun:a.example.com", "stuns:b.example.com"]
+        );
+        assert!(serde_json::from_str::<IceServer>(r#"{"urls":1}"#).is_err());
+    }
+
+    #[test]
+    fn turn_requires_credentials() {
+        assert!(server("turn:t.example.com:3478", None, None).validate().is_err());
+        assert!(server("turns:t.example.com", Some("user"), None).validate().is_err());
+        assert!(server("turn:t.example.com", Some(""), Some("pass")).validate().is_err());
+        assert!(server("turn:t.example.com", Some("user"), Some("pass")).validate().is_ok());
+        assert!(server("stun:s.example.com", None, None).validate().is_ok());
+    }
+
+    #[test]
+    fn rejects_unsupported_schemes() {
+        assert!(server("http://s.example.com", None, None).validate().is_err());
+        assert!(server("s.example.com", None, None).validate().is_err());
+    }
+
+    #[test]
+    fn rejects_urls_without_host() {
+        assert!(server("stun:", None, None).validate().is_err());
+        assert!(server("turn:", Some("user"), Some("pass")).validate().is_err());
+    }
+
+    #[test]
+    fn rejects_empty_urls() {
+        assert!(parse(r#"{"urls":[]}"#).validate().is_err());
+        // 一覧自体が空なのは ICE サーバー無しとして許可する
+        assert!(IceServerList(vec![]).validate().is_ok());
+        assert!(IceServerList::default().validate().is_ok());
+    }
+}
@@ -1,223 +1,1333 @@
 use wasm_bindgen::prelude::*;
 use wasm_bindgen_futures::spawn_local;
-use web_sys::{WebSocket, MessageEvent, ErrorEvent, console, RtcSessionDescriptionInit, RtcSdpType, RtcIceCandidateInit};
-use wasm_bindgen::JsValue;
+use web_sys::{WebSocket, MessageEvent, ErrorEvent, CloseEvent, MediaStream, Element, HtmlVideoElement, console, RtcSdpType, RtcSessionDescriptionInit};
 mod webrtc_peer_connection;
-use webrtc_peer_connection::WebRTCConnection;
-use js_sys::JsString;
-use serde_json;
-use std::sync::Arc;
+use webrtc_peer_connection::{CandidateError, IceRecovery, RemoteDescription, WebRTCConnection};
+mod signaling;
+use signaling::{Envelope, SignalingError, SignalingMessage, TrackKind};
+mod socket;
+use socket::{SharedSocket, SignalingSender};
+mod room;
+use room::Room;
+mod reconnect;
+use reconnect::ReconnectPolicy;
+mod outbound_queue;
+use outbound_queue::OutboundMessage;
+mod close;
+use close::{CloseInfo, CloseKind};
+mod heartbeat;
+mod ice_servers;
+use ice_servers::IceServerList;
+use heartbeat::{HeartbeatFrame, HeartbeatMonitor};
+mod options;
+use options::ClientOptions;
+mod status;
+use status::ConnectionStatus;
+mod dom;
+use dom::DomBinding;
+mod media;
+use media::MediaOptions;
+mod screen_share;
+use screen_share::ScreenShare;
+mod data_channel;
+use data_channel::DataChannel;
+mod sha256;
+mod timer;
+mod stats;
+mod file_transfer;
+use file_transfer::FileTransfers;
+mod error;
+use error::ClientError;
+mod waiters;
+use waiters::Waiters;
+mod mute;
+use mute::MuteState;
+mod codecs;
+use codecs::CodecOrder;
+mod send_limits;
+use send_limits::SendLimitsByKind;
 use wasm_bindgen::JsCast;
-use crate::webrtc_peer_connection::start_camera;
+use std::cell::{Cell, RefCell};
+use std::rc::{Rc, Weak};
 
 #[wasm_bindgen]
 pub struct WebSocketClient {
-    peerconnection: WebRTCConnection,
-    ws: WebSocket,
+    inner: Rc<ClientInner>,
 }
 
+// JSから登録されたコールバック
+#[derive(Default)]
+struct Callbacks {
+    on_open: Option<js_sys::Function>,
+    on_message: Option<js_sys::Function>,
+    on_error: Option<js_sys::Function>,
+    on_close: Option<js_sys::Function>,
+    on_reconnecting: Option<js_sys::Function>,
+    on_reconnected: Option<js_sys::Function>,
+    on_connection_lost: Option<js_sys::Function>,
+    on_binary: Option<js_sys::Function>,
+    on_peer_joined: Option<js_sys::Function>,
+    on_peer_left: Option<js_sys::Function>,
+    on_peer_track: Option<js_sys::Function>,
+    on_ice_candidate_error: Option<js_sys::Function>,
+    on_local_stream: Option<js_sys::Function>,
+    on_remote_track: Option<js_sys::Function>,
+    on_status_change: Option<js_sys::Function>,
+    on_screen_share_ended: Option<js_sys::Function>,
+    on_data_channel: Option<js_sys::Function>,
+    on_file_progress: Option<js_sys::Function>,
+    on_file_received: Option<js_sys::Function>,
+    on_file_sent: Option<js_sys::Function>,
+    on_file_error: Option<js_sys::Function>,
+    on_remote_mute: Option<js_sys::Function>,
+    // Rust側から登録するバイナリハンドラー
+    binary_handler: Option<Rc<dyn Fn(Vec<u8>)>>,
+}
+
+// ソケットに登録するクロージャ。再接続時は新しいソケットに付け替える
+struct SocketClosures {
+    on_open: Closure<dyn Fn(JsValue)>,
+    on_message: Closure<dyn Fn(MessageEvent)>,
+    on_error: Closure<dyn Fn(ErrorEvent)>,
+    on_close: Closure<dyn Fn(CloseEvent)>,
+    // ハートビートの setInterval 用
+    heartbeat_tick: Closure<dyn Fn()>,
+}
+
+struct ClientInner {
+    url: String,
+    // シグナリング上の自分のID
+    peer_id: String,
+    socket: SharedSocket,
+    // ルーム外 (1対1) の接続
+    peerconnection: RefCell<WebRTCConnection>,
+    room: RefCell<Room>,
+    ice_servers: RefCell<IceServerList>,
+    local_stream: RefCell<Option<MediaStream>>,
+    // bind_elements で指定された表示先 (未指定なら DOM には触らない)
+    dom: RefCell<DomBinding>,
+    screen_share: RefCell<Option<ScreenShare>>,
+    // set_audio_enabled / set_video_enabled でオフにしているトラック
+    muted: RefCell<MuteState>,
+    // set_codec_preferences で指定したコーデックの優先順 (新しい接続にも適用する)
+    codec_order: RefCell<CodecOrder>,
+    // set_send_limits で指定した送信の上限 (新しい接続にも適用する)
+    send_limits: RefCell<SendLimitsByKind>,
+    file_transfers: RefCell<FileTransfers>,
+    reconnect: ReconnectPolicy,
+    // 現在の再接続試行回数 (0 なら接続中または初回接続)
+    attempt: Cell<u32>,
+    // 再接続のタイマーを設定済みで、まだ新しいソケットを開いていない
+    reconnect_pending: Cell<bool>,
+    closed_by_user: Cell<bool>,
+    // destroy 済みなら true
+    destroyed: Cell<bool>,
+    heartbeat: RefCell<HeartbeatMonitor>,
+    heartbeat_timer: Cell<Option<i32>>,
+    callbacks: RefCell<Callbacks>,
+    closures: RefCell<Option<SocketClosures>>,
+    // connect() の呼び出し (ソケットが開くか失敗するまで待つ)
+    open_waiters: Waiters,
+    // answer() の呼び出し (次に answer を送るまで待つ)
+    answer_waiters: Waiters,
+}
+
+// connect() / send() がソケットが開くのを待つ既定の時間
+const DEFAULT_CONNECT_TIMEOUT_MS: u32 = 10_000;
+// answer() が相手のオファーを待つ既定の時間
+const DEFAULT_ANSWER_TIMEOUT_MS: u32 = 30_000;
+
 #[wasm_bindgen]
 impl WebSocketClient {
     #[wasm_bindgen(constructor)]
-    pub fn new(url: &str) -> Result<WebSocketClient, JsValue> {
+    pub fn new(url: &str, options: JsValue) -> Result<WebSocketClient, ClientError> {
         // Create WebSocket connection
         let formatted_log = format!("url: {}", url);
         console::log_1(&formatted_log.into());
 
-        // craete webrtc peerconnection
+        let options = ClientOptions::from_js(options).map_err(ClientError::protocol)?;
 
         // create websocket
-        let ws = match WebSocket::new(url) {
+        let ws = match open_socket(url) {
             Ok(socket) => { 
                 console::log_1(&"WebSocket connection create.".into());
                 socket 
             } 
             Err(err) => {
                 console::log_1(&format!("Failed to connect to WebSocket: {:?}", err).into());
-                return Err(err);
+                return Err(ClientError::transport(err));
             }
         };
-        console::log_1(&"WebSocket connection create.".into());
-
-        // Set binary type to arraybuffer
-        ws.set_binary_type(web_sys::BinaryType::Arraybuffer);
-        console::log_1(&"WebSocket set binary type.".into());
 
-        let peer = WebRTCConnection::new(ws.clone()).unwrap();
+        // craete webrtc peerconnection
+        let peer_id = options.peer_id.clone().unwrap_or_else(generate_peer_id);
+        let socket = SharedSocket::with_queue(ws.clone(), options.queue.clone());
+        let peer = WebRTCConnection::with_signaling(
+            SignalingSender::routed(socket.clone(), &peer_id, None, None),
+            &options.ice_servers,
+        )
+        .map_err(ClientError::negotiation)?;
         console::log_1(&"WebRtc connection create.".into());
 
-        let peer_clone = peer.clone();
-        spawn_local(async move {
-            if let Err(e) = start_camera(peer_clone).await {
-                web_sys::console::error_1(&e);
-            }
+        let inner = Rc::new(ClientInner {
+            url: url.to_string(),
+            peer_id,
+            socket,
+            peerconnection: RefCell::new(peer),
+            room: RefCell::new(Room::default()),
+            ice_servers: RefCell::new(options.ice_servers.clone()),
+            local_stream: RefCell::new(None),
+            dom: RefCell::new(DomBinding::default()),
+            screen_share: RefCell::new(None),
+            muted: RefCell::new(MuteState::default()),
+            codec_order: RefCell::new(CodecOrder::default()),
+            send_limits: RefCell::new(SendLimitsByKind::default()),
+            file_transfers: RefCell::new(FileTransfers::default()),
+            reconnect: options.reconnect,
+            attempt: Cell::new(0),
+            reconnect_pending: Cell::new(false),
+            closed_by_user: Cell::new(false),
+            destroyed: Cell::new(false),
+            heartbeat: RefCell::new(HeartbeatMonitor::new(options.heartbeat)),
+            heartbeat_timer: Cell::new(None),
+            callbacks: RefCell::new(Callbacks::default()),
+            closures: RefCell::new(None),
+            open_waiters: Waiters::default(),
+            answer_waiters: Waiters::default(),
         });
+        inner.closures.replace(Some(SocketClosures::new(Rc::downgrade(&inner))));
+        inner.attach(&ws);
+        inner.configure_default_connection(&inner.default_connection());
 
-        // 
-        Ok(WebSocketClient {peerconnection: peer, ws })
+        Ok(WebSocketClient { inner })
     }
 
-    pub fn send_message(&self, message: &str) -> Result<(), JsValue> {
+    // シグナリングサーバーへの接続が開くのを待つ
+    // 開いたら resolve し、エラー・切断・timeoutMs (既定 10 秒) 経過で reject する
+    pub async fn connect(&self, timeout_ms: Option<u32>) -> Result<(), ClientError> {
+        if self.inner.closed_by_user.get() {
+            return Err(ClientError::Transport("client has been closed".to_string()));
+        }
+        if self.inner.socket.is_open() {
+            return Ok(());
+        }
+        // 閉じていて再接続の予定も無ければ待っても開かない
+        if self.inner.socket.is_closed() && !self.inner.reconnect_pending.get() {
+            return Err(ClientError::Transport("WebSocket is closed and no reconnect is scheduled".to_string()));
+        }
+        let timeout_ms = timeout_ms.unwrap_or(DEFAULT_CONNECT_TIMEOUT_MS);
+        let timeout = ClientError::Transport(format!("WebSocket did not open within {} ms", timeout_ms));
+        self.inner.open_waiters.wait(timeout_ms, timeout).await?;
+        Ok(())
+    }
+
+    // data (string / Uint8Array / ArrayBuffer) を送信する
+    // 未接続なら connect() と同じく接続を待ち、ソケットに渡した時点で resolve する
+    pub async fn send(&self, data: JsValue, timeout_ms: Option<u32>) -> Result<(), ClientError> {
+        let message = if let Some(text) = data.as_string() {
+            OutboundMessage::Text(text)
+        } else if let Some(buffer) = data.dyn_ref::<js_sys::ArrayBuffer>() {
+            OutboundMessage::Binary(js_sys::Uint8Array::new(buffer).to_vec())
+        } else if let Some(bytes) = data.dyn_ref::<js_sys::Uint8Array>() {
+            OutboundMessage::Binary(bytes.to_vec())
+        } else {
+            return Err(ClientError::Protocol("send() expects a string, Uint8Array or ArrayBuffer".to_string()));
+        };
+        self.connect(timeout_ms).await?;
+        self.inner.socket.send(message).map_err(ClientError::transport)
+    }
+
+    pub fn send_message(&self, message: &str) -> Result<(), ClientError> {
         console::log_1(&format!("Sending message to WebSocket: {:?}", message).into());
 
-        self.ws.send_with_str(message)
+        self.inner.socket.send_with_str(message).map_err(ClientError::transport)
+    }
+
+    // バイナリメッセージを送信 (JSからは Uint8Array を渡す)
+    pub fn send_bytes(&self, data: &[u8]) -> Result<(), ClientError> {
+        console::log_1(&format!("Sending {} bytes to WebSocket", data.len()).into());
+        self.inner.socket.send_with_u8_array(data).map_err(ClientError::transport)
+    }
+
+    pub fn send_array_buffer(&self, buffer: &js_sys::ArrayBuffer) -> Result<(), ClientError> {
+        self.send_bytes(&js_sys::Uint8Array::new(buffer).to_vec())
     }
 
     pub fn on_open(&self, callback: js_sys::Function) -> Result<(), JsValue> {
         console::log_1(&"WebSocket on open.".into());
+        self.inner.callbacks.borrow_mut().on_open = Some(callback);
+        Ok(())
+    }
+
+    pub fn on_message(&self, callback: js_sys::Function) -> Result<(), JsValue> {
+        self.inner.callbacks.borrow_mut().on_message = Some(callback);
+        Ok(())
+    }
+
+    // バイナリメッセージ受信時に Uint8Array で呼ばれる
+    pub fn on_binary(&self, callback: js_sys::Function) -> Result<(), JsValue> {
+        self.inner.callbacks.borrow_mut().on_binary = Some(callback);
+        Ok(())
+    }
+
+    // ソケットのエラーや、バックグラウンドの処理 (ネゴシエーションなど) の失敗で呼ばれる
+    // 引数は code ("transport" | "signaling" | "negotiation" | "media" | "permission" | "protocol") 付きの Error
+    pub fn on_error(&self, callback: js_sys::Function) -> Result<(), JsValue> {
+        self.inner.callbacks.borrow_mut().on_error = Some(callback);
+        Ok(())
+    }
+
+    // 再接続を試みる直前に (attempt, delayMs) で呼ばれる
+    pub fn on_reconnecting(&self, callback: js_sys::Function) -> Result<(), JsValue> {
+        self.inner.callbacks.borrow_mut().on_reconnecting = Some(callback);
+        Ok(())
+    }
+
+    // 再接続に成功したときに (attempt) で呼ばれる
+    pub fn on_reconnected(&self, callback: js_sys::Function) -> Result<(), JsValue> {
+        self.inner.callbacks.borrow_mut().on_reconnected = Some(callback);
+        Ok(())
+    }
+
+    // 接続待ちで送信されずに溜まっているメッセージ数
+    pub fn queue_depth(&self) -> usize {
+        self.inner.socket.queue_depth()
+    }
+
+    // overflow: "callback" のとき、キューに入らなかったメッセージで呼ばれる
+    pub fn on_queue_overflow(&self, callback: js_sys::Function) -> Result<(), JsValue> {
+        self.inner.socket.set_on_overflow(Some(callback));
+        Ok(())
+    }
+
+    // 切断時に { code, reason, wasClean, kind, willReconnect } で呼ばれる
+    // kind: "normal" | "goingAway" | "policyViolation" | "abnormal" | "other"
+    pub fn on_close(&self, callback: js_sys::Function) -> Result<(), JsValue> {
+        self.inner.callbacks.borrow_mut().on_close = Some(callback);
+        Ok(())
+    }
+
+    // ハートビートの応答が無く、接続が切れたと判断したときに呼ばれる
+    pub fn on_connection_lost(&self, callback: js_sys::Function) -> Result<(), JsValue> {
+        self.inner.callbacks.borrow_mut().on_connection_lost = Some(callback);
+        Ok(())
+    }
+
+    pub fn close(&self) -> Result<(), ClientError> {
+        self.inner.closed_by_user.set(true);
+        self.inner.stop_heartbeat();
+        self.inner.socket.get().close().map_err(ClientError::transport)
+    }
+
+    // code は 1000 または 3000-4999、reason は UTF-8 で 123 バイトまで
+    pub fn close_with(&self, code: u16, reason: &str) -> Result<(), ClientError> {
+        close::validate_close(code, reason).map_err(ClientError::Protocol)?;
+        self.inner.closed_by_user.set(true);
+        self.inner.stop_heartbeat();
+        self.inner.socket.get().close_with_code_and_reason(code, reason).map_err(ClientError::transport)
+    }
+
+    // カメラ・マイクを取得して全ての接続に追加する
+    // constraints: { audio, video, width, height, frameRate, facingMode, deviceId, audioDeviceId }
+    // 省略時は音声と映像の両方。コンストラクタではキャプチャしないので、送信する側はこれを呼ぶ
+    pub async fn start_media(&self, constraints: JsValue) -> Result<MediaStream, ClientError> {
+        let options = MediaOptions::from_js(constraints).map_err(ClientError::protocol)?;
+        if self.inner.local_stream.borrow().is_some() {
+            return Err(ClientError::Media("media already started; call stop_media first".to_string()));
+        }
+        let stream = match media::get_user_media(&options).await {
+            Ok(stream) => stream,
+            Err(e) => {
+                self.inner.emit_status(ConnectionStatus::LocalMediaFailed, None);
+                return Err(ClientError::media(e));
+            }
+        };
+        self.inner.emit_local_stream(&stream);
+        self.inner.set_local_stream(stream.clone());
+        Ok(stream)
+    }
+
+    // カメラ・マイクの一覧を [{ deviceId, groupId, kind, label }] で返す
+    // kind は "audio" または "video"。label は start_media で許可を得るまで空のことがある
+    pub async fn list_devices(&self) -> Result<JsValue, ClientError> {
+        let devices = media::enumerate_input_devices().await.map_err(ClientError::media)?;
+        serde_wasm_bindgen::to_value(&devices).map_err(|e| ClientError::Media(e.to_string()))
+    }
+
+    // 通話中にカメラ (kind = "video") またはマイク (kind = "audio") を切り替える
+    // 全ての接続の送信トラックを replaceTrack で差し替えるので再ネゴシエーションは起きない
+    pub async fn switch_device(&self, kind: String, device_id: String) -> Result<(), ClientError> {
+        let options = MediaOptions::for_device(&kind, &device_id).map_err(ClientError::Protocol)?;
+        let Some(stream) = self.inner.local_stream.borrow().clone() else {
+            return Err(ClientError::Media("media not started; call start_media first".to_string()));
+        };
+        let captured = media::get_user_media(&options).await.map_err(ClientError::media)?;
+        let Some(track) = captured.get_tracks().iter().find_map(|track| track.dyn_into::<web_sys::MediaStreamTrack>().ok()) else {
+            return Err(ClientError::Media("no track was captured".to_string()));
+        };
+        // オフにしている間に切り替えても相手に映像・音声を送らない
+        if let Some(kind) = TrackKind::parse(&track.kind()) {
+            track.set_enabled(self.inner.is_track_enabled(kind));
+        }
+
+        // 画面共有中は映像の送信トラックを差し替えない (共有終了時に新しいカメラへ戻る)
+        let sharing_screen = track.kind() == "video" && self.inner.screen_share.borrow().is_some();
+        if !sharing_screen {
+            for connection in self.inner.connections() {
+                if let Err(e) = connection.replace_track(track.clone()).await {
+                    self.inner.report_error(ClientError::media(e));
+                }
+            }
+        }
+        // プレビューは同じ MediaStream を表示しているので、トラックを入れ替えれば更新される
+        for old in stream.get_tracks().iter().filter_map(|track| track.dyn_into::<web_sys::MediaStreamTrack>().ok()) {
+            if old.kind() == track.kind() {
+                stream.remove_track(&old);
+                old.stop();
+            }
+        }
+        stream.add_track(&track);
+        Ok(())
+    }
+
+    // 画面共有を開始し、全ての接続の映像トラックを画面のトラックに差し替える
+    // 戻り値の MediaStream はプレビュー表示用
+    pub async fn start_screen_share(&self) -> Result<MediaStream, ClientError> {
+        self.inner.start_screen_share().await
+    }
+
+    // 画面共有を止めてカメラの映像に戻す
+    pub async fn stop_screen_share(&self) {
+        self.inner.stop_screen_share().await;
+    }
+
+    pub fn is_screen_sharing(&self) -> bool {
+        self.inner.screen_share.borrow().is_some()
+    }
+
+    // ブラウザの UI から画面共有が止められたときに呼ばれる
+    pub fn on_screen_share_ended(&self, callback: js_sys::Function) -> Result<(), JsValue> {
+        self.inner.callbacks.borrow_mut().on_screen_share_ended = Some(callback);
+        Ok(())
+    }
+
+    // キャプチャ中のトラックを全て止める
+    pub fn stop_media(&self) {
+        self.inner.stop_media();
+    }
+
+    // マイクのオン・オフ。相手には mute メッセージで知らせる (on_remote_mute で受け取る)
+    // stopCapture が true ならトラックを止めてマイクを解放し、オンに戻すときに取り直す
+    pub async fn set_audio_enabled(&self, enabled: bool, stop_capture: Option<bool>) -> Result<(), ClientError> {
+        self.inner.set_track_enabled(TrackKind::Audio, enabled, stop_capture.unwrap_or(false)).await
+    }
+
+    // カメラのオン・オフ。stopCapture が true ならカメラを止める (カメラのランプも消える)
+    pub async fn set_video_enabled(&self, enabled: bool, stop_capture: Option<bool>) -> Result<(), ClientError> {
+        self.inner.set_track_enabled(TrackKind::Video, enabled, stop_capture.unwrap_or(false)).await
+    }
+
+    pub fn is_audio_enabled(&self) -> bool {
+        self.inner.is_track_enabled(TrackKind::Audio)
+    }
+
+    pub fn is_video_enabled(&self) -> bool {
+        self.inner.is_track_enabled(TrackKind::Video)
+    }
+
+    // 相手がマイク・カメラをオフ・オンにしたときに (kind, muted, peerId) で呼ばれる
+    // kind は "audio" または "video"。1対1の相手なら peerId は null
+    pub fn on_remote_mute(&self, callback: js_sys::Function) -> Result<(), JsValue> {
+        self.inner.callbacks.borrow_mut().on_remote_mute = Some(callback);
+        Ok(())
+    }
+
+    // ブラウザが送信できるコーデック (kind は "audio" または "video")
+    // [{ mimeType, clockRate, channels, sdpFmtpLine }] の配列
+    pub fn codecs(&self, kind: &str) -> Result<JsValue, ClientError> {
+        webrtc_peer_connection::available_codecs(kind)
+    }
+
+    // 全ての接続でのコーデックの優先順を設定する (例: set_codec_preferences("video", ["video/VP9", "video/H264"]))
+    // 空の配列なら既定に戻す。通話中に変えた場合は次のネゴシエーション (offer) から有効になる
+    pub fn set_codec_preferences(&self, kind: &str, mime_types: Vec<String>) -> Result<(), ClientError> {
+        let kind = webrtc_peer_connection::parse_kind(kind)?;
+        let order = codecs::normalize(kind, mime_types).map_err(ClientError::Media)?;
+        self.inner
+            .codec_order
+            .borrow_mut()
+            .set(kind, if order.is_empty() { None } else { Some(order.clone()) });
+        for connection in self.inner.connections() {
+            connection.set_codec_order(kind, order.clone())?;
+        }
+        Ok(())
+    }
+
+    // 全ての接続の送信に上限を設定する (例: set_send_limits("video", { maxBitrate: 300_000, maxFramerate: 15 }))
+    // 通話中でも再ネゴシエーションなしで反映される。null または {} なら制限なし
+    pub async fn set_send_limits(&self, kind: String, limits: JsValue) -> Result<(), ClientError> {
+        let kind = webrtc_peer_connection::parse_kind(&kind)?;
+        let limits = webrtc_peer_connection::parse_send_limits(kind, limits)?;
+        self.inner.send_limits.borrow_mut().set(kind, limits.clone());
+        for connection in self.inner.connections() {
+            connection.set_send_limits_of(kind, limits.clone()).await?;
+        }
+        Ok(())
+    }
+
+    // オファーを送信し、送信したオファー ({ type, sdp }) で resolve する
+    // オファー作成中に呼んだ場合や、相手のオファーと衝突して送らなかった場合は null
+    pub async fn offer(&self) -> Result<Option<RtcSessionDescriptionInit>, ClientError> {
+        let connection = self.inner.default_connection();
+        let offer = connection.negotiate().await?;
+        if offer.is_some() {
+            self.inner.announce_mute_state(&connection);
+        }
+        Ok(offer)
+    }
+
+    // 相手のオファーに answer を送るまで待ち、送信した answer ({ type, sdp }) で resolve する
+    // answer は受信時に自動で送られるので、既に応答済みならその answer をすぐに返す
+    // timeoutMs (既定 30 秒) 以内にオファーが来なければ reject する
+    pub async fn answer(&self, timeout_ms: Option<u32>) -> Result<JsValue, ClientError> {
+        if let Some(answer) = self.inner.default_connection().current_answer() {
+            return Ok(answer.into());
+        }
+        let timeout_ms = timeout_ms.unwrap_or(DEFAULT_ANSWER_TIMEOUT_MS);
+        let timeout = ClientError::Negotiation(format!("no offer was answered within {} ms", timeout_ms));
+        self.inner.answer_waiters.wait(timeout_ms, timeout).await
+    }
+
+    // 通話を終了する。相手に bye を送り、ルームから抜け、接続・カメラ・画面共有・ファイル転送を止める
+    // シグナリングの接続は残るので、続けて start_media / offer で新しい通話を始められる
+    pub fn hang_up(&self) -> Result<(), ClientError> {
+        self.inner.hang_up()
+    }
+
+    // hang_up に加えてシグナリングの接続も閉じ、登録されたコールバックとイベントハンドラーを全て解放する
+    // 以降このクライアントは使えない。free() されたときにも呼ばれる
+    pub fn destroy(&self) {
+        self.inner.destroy();
+    }
+
+    // ICE サーバー ([{ urls, username, credential }]) を差し替える
+    // 既存の接続にも適用され、新しい接続はこの設定で作られる
+    pub fn set_ice_servers(&self, ice_servers: JsValue) -> Result<(), ClientError> {
+        let ice_servers = webrtc_peer_connection::parse_ice_servers(ice_servers).map_err(ClientError::protocol)?;
+        for connection in self.inner.connections() {
+            connection.apply_ice_servers(&ice_servers).map_err(ClientError::negotiation)?;
+        }
+        self.inner.ice_servers.replace(ice_servers);
+        Ok(())
+    }
+
+    // シグナリング上の自分のピアID
+    pub fn peer_id(&self) -> String {
+        self.inner.peer_id.clone()
+    }
+
+    // ルームに参加する。既存のメンバーからそれぞれオファーが届く
+    pub fn join_room(&self, room: &str) -> Result<(), ClientError> {
+        self.inner.join_room(room)
+    }
+
+    pub fn leave_room(&self) -> Result<(), ClientError> {
+        self.inner.leave_room()
+    }
+
+    pub fn room(&self) -> Option<String> {
+        self.inner.room.borrow().name.clone()
+    }
+
+    // ルーム内で接続しているピアIDの一覧
+    pub fn peers(&self) -> js_sys::Array {
+        self.inner.room.borrow().peers.keys().map(|id| JsValue::from_str(id)).collect()
+    }
+
+    // データチャネルを作る (peerId を省略すると1対1の接続に作る)
+    // 通話が確立した後のアプリのメッセージをシグナリングサーバーを通さずに送れる
+    pub fn create_data_channel(&self, label: &str, options: JsValue, peer_id: Option<String>) -> Result<DataChannel, ClientError> {
+        let Some(connection) = self.connection(peer_id.clone()) else {
+            return Err(ClientError::Protocol(format!("unknown peer: {}", peer_id.unwrap_or_default())));
+        };
+        connection.create_data_channel(label, options).map_err(ClientError::protocol)
+    }
+
+    // 相手がデータチャネルを作ったときに (DataChannel, peerId) で呼ばれる
+    // 1対1の接続では peerId は null
+    pub fn on_data_channel(&self, callback: js_sys::Function) -> Result<(), JsValue> {
+        self.inner.callbacks.borrow_mut().on_data_channel = Some(callback);
+        Ok(())
+    }
+
+    // 接続を取得する (peerId を省略すると1対1の接続)
+    // トランシーバーや送信トラックを個別に操作するときに使う
+    pub fn connection(&self, peer_id: Option<String>) -> Option<WebRTCConnection> {
+        self.inner.connection_for(peer_id.as_deref())
+    }
+
+    // ファイルを送信し、転送IDを返す (peerId を省略すると1対1の接続で送る)
+    // ファイルは 16KiB ずつ読んで送るので、全体をメモリに載せない
+    pub fn send_file(&self, file: web_sys::File, peer_id: Option<String>) -> Result<String, ClientError> {
+        let name = file.name();
+        self.inner.send_file(file.into(), name, peer_id)
+    }
+
+    // 送信中・受信中のファイル転送を中止する
+    pub fn cancel_file(&self, id: &str) -> Result<(), ClientError> {
+        self.inner.cancel_file(id)
+    }
+
+    // 切断で止まった送信をすぐに再開する (通常は自動で再開される)
+    pub fn resume_file(&self, id: &str) -> Result<(), ClientError> {
+        self.inner.resume_file(id)
+    }
+
+    // 送受信の進捗 ({ id, name, size, mimeType, peerId }, bytes, "send" | "receive")
+    pub fn on_file_progress(&self, callback: js_sys::Function) -> Result<(), JsValue> {
+        self.inner.callbacks.borrow_mut().on_file_progress = Some(callback);
+        Ok(())
+    }
+
+    // ファイルを受信し、ハッシュが一致したときに (info, Blob) で呼ばれる
+    pub fn on_file_received(&self, callback: js_sys::Function) -> Result<(), JsValue> {
+        self.inner.callbacks.borrow_mut().on_file_received = Some(callback);
+        Ok(())
+    }
+
+    // 相手がファイルを受信し終えたときに (info) で呼ばれる
+    pub fn on_file_sent(&self, callback: js_sys::Function) -> Result<(), JsValue> {
+        self.inner.callbacks.borrow_mut().on_file_sent = Some(callback);
+        Ok(())
+    }
+
+    // 転送が失敗・中断したときに (info, message, resumable) で呼ばれる
+    pub fn on_file_error(&self, callback: js_sys::Function) -> Result<(), JsValue> {
+        self.inner.callbacks.borrow_mut().on_file_error = Some(callback);
+        Ok(())
+    }
+
+    // ルーム内の特定のピアにオファーを送る (再ネゴシエーション用)
+    pub async fn offer_to(&self, peer_id: String) -> Result<Option<RtcSessionDescriptionInit>, ClientError> {
+        let connection = self.inner.room_peer(&peer_id).map_err(ClientError::negotiation)?;
+        connection.negotiate().await
+    }
+
+    // リモートの ICE candidate を追加できなかったときに
+    // { peerId, candidate, sdpMid, sdpMLineIndex, message } で呼ばれる
+    pub fn on_ice_candidate_error(&self, callback: js_sys::Function) -> Result<(), JsValue> {
+        self.inner.callbacks.borrow_mut().on_ice_candidate_error = Some(callback);
+        Ok(())
+    }
+
+    // ルームに新しいピアが現れたときに (peerId) で呼ばれる
+    pub fn on_peer_joined(&self, callback: js_sys::Function) -> Result<(), JsValue> {
+        self.inner.callbacks.borrow_mut().on_peer_joined = Some(callback);
+        Ok(())
+    }
+
+    // ピアがルームから抜けたときに (peerId) で呼ばれる
+    pub fn on_peer_left(&self, callback: js_sys::Function) -> Result<(), JsValue> {
+        self.inner.callbacks.borrow_mut().on_peer_left = Some(callback);
+        Ok(())
+    }
+
+    // ルーム内のピアからメディアを受信したときに (peerId, MediaStream) で呼ばれる
+    pub fn on_peer_track(&self, callback: js_sys::Function) -> Result<(), JsValue> {
+        self.inner.callbacks.borrow_mut().on_peer_track = Some(callback);
+        Ok(())
+    }
+
+    // カメラの映像を取得したときに (MediaStream) で呼ばれる
+    pub fn on_local_stream(&self, callback: js_sys::Function) -> Result<(), JsValue> {
+        self.inner.callbacks.borrow_mut().on_local_stream = Some(callback);
+        Ok(())
+    }
+
+    // リモートのメディアを受信したときに (MediaStream, peerId) で呼ばれる
+    // 1対1の接続では peerId は null
+    pub fn on_remote_track(&self, callback: js_sys::Function) -> Result<(), JsValue> {
+        self.inner.callbacks.borrow_mut().on_remote_track = Some(callback);
+        Ok(())
+    }
+
+    // 接続状態が変わったときに (status, peerId) で呼ばれる
+    // status は "signalingConnected" / "offerReceived" などの文字列
+    pub fn on_status_change(&self, callback: js_sys::Function) -> Result<(), JsValue> {
+        self.inner.callbacks.borrow_mut().on_status_change = Some(callback);
+        Ok(())
+    }
+
+    // 任意: 映像と状態を表示する要素を指定する (不要なものは null)
+    pub fn bind_elements(
+        &self,
+        local_video: Option<HtmlVideoElement>,
+        remote_video: Option<HtmlVideoElement>,
+        status: Option<Element>,
+    ) {
+        let dom = DomBinding { local_video, remote_video, status };
+        if let Some(stream) = self.inner.local_stream.borrow().as_ref() {
+            dom.show_local_stream(stream);
+        }
+        self.inner.dom.replace(dom);
+    }
 
-        let cloned_ws = self.ws.clone();
-        let closure = Closure::wrap(Box::new(move |_event: JsValue| {
-            let _ = callback.call0(&cloned_ws);
+}
+
+impl WebSocketClient {
+    // Rust側でバイナリメッセージ (protobuf / CBOR など) を受け取るハンドラーを登録
+    pub fn set_binary_handler<F: Fn(Vec<u8>) + 'static>(&self, handler: F) {
+        self.inner.callbacks.borrow_mut().binary_handler = Some(Rc::new(handler));
+    }
+}
+
+impl Drop for WebSocketClient {
+    // JS から free() されたら、ソケットや接続に登録したハンドラーが残らないように片付ける
+    fn drop(&mut self) {
+        self.inner.destroy();
+    }
+}
+
+impl SocketClosures {
+    fn new(client: Weak<ClientInner>) -> SocketClosures {
+        let weak = client.clone();
+        let on_open = Closure::wrap(Box::new(move |_event: JsValue| {
+            if let Some(inner) = weak.upgrade() {
+                inner.handle_open();
+            }
         }) as Box<dyn Fn(JsValue)>);
 
-        self.ws.set_onopen(Some(closure.as_ref().unchecked_ref()));
-        closure.forget();
+        let weak = client.clone();
+        let on_message = Closure::wrap(Box::new(move |event: MessageEvent| {
+            if let Some(inner) = weak.upgrade() {
+                inner.handle_message(event);
+            }
+        }) as Box<dyn Fn(MessageEvent)>);
+
+        let weak = client.clone();
+        let on_error = Closure::wrap(Box::new(move |_event: ErrorEvent| {
+            // WebSocket の error イベントには詳細が無いので、原因は続く close イベントで分かる
+            if let Some(inner) = weak.upgrade() {
+                let error = ClientError::Transport("WebSocket error".to_string());
+                inner.open_waiters.settle(Err(error.clone()));
+                inner.report_error(error);
+            }
+        }) as Box<dyn Fn(ErrorEvent)>);
+
+        let weak = client.clone();
+        let on_close = Closure::wrap(Box::new(move |event: CloseEvent| {
+            if let Some(inner) = weak.upgrade() {
+                inner.handle_close(event);
+            }
+        }) as Box<dyn Fn(CloseEvent)>);
+
+        let heartbeat_tick = Closure::wrap(Box::new(move || {
+            if let Some(inner) = client.upgrade() {
+                inner.heartbeat_tick();
+            }
+        }) as Box<dyn Fn()>);
+
+        SocketClosures { on_open, on_message, on_error, on_close, heartbeat_tick }
+    }
+}
+
+impl ClientInner {
+    // 各 WebRTCConnection の candidate エラーを on_ice_candidate_error に転送する
+    fn candidate_error_handler(self: &Rc<Self>) -> Rc<dyn Fn(&CandidateError)> {
+        let weak = Rc::downgrade(self);
+        Rc::new(move |error: &CandidateError| {
+            let Some(inner) = weak.upgrade() else {
+                return;
+            };
+            let callback = inner.callbacks.borrow().on_ice_candidate_error.clone();
+            if let Some(callback) = callback {
+                match serde_wasm_bindgen::to_value(error) {
+                    Ok(value) => {
+                        let _ = callback.call1(&JsValue::NULL, &value);
+                    }
+                    Err(e) => console::error_1(&e.into()),
+                }
+            }
+        })
+    }
+
+    // 1対1の接続とルーム内の全ての接続
+    fn data_channel_handler(self: &Rc<Self>, peer_id: Option<&str>) -> Rc<dyn Fn(DataChannel)> {
+        let weak = Rc::downgrade(self);
+        let peer_id = peer_id.map(str::to_string);
+        Rc::new(move |channel: DataChannel| {
+            let Some(inner) = weak.upgrade() else {
+                return;
+            };
+            // ファイル転送用のチャネルはアプリに渡さない
+            if channel.label().starts_with(file_transfer::LABEL_PREFIX) {
+                inner.accept_file_channel(channel, peer_id.clone());
+                return;
+            }
+            let callback = inner.callbacks.borrow().on_data_channel.clone();
+            if let Some(callback) = callback {
+                let peer_id = peer_id.as_deref().map_or(JsValue::NULL, JsValue::from_str);
+                let _ = callback.call2(&JsValue::NULL, &channel.into(), &peer_id);
+            }
+        })
+    }
+
+    // ICE restart の経過を on_status_change で知らせる
+    fn ice_recovery_handler(self: &Rc<Self>, peer_id: Option<&str>) -> Rc<dyn Fn(IceRecovery)> {
+        let weak = Rc::downgrade(self);
+        let peer_id = peer_id.map(str::to_string);
+        Rc::new(move |recovery: IceRecovery| {
+            let Some(inner) = weak.upgrade() else {
+                return;
+            };
+            let status = match recovery {
+                IceRecovery::Reconnecting => ConnectionStatus::IceReconnecting,
+                IceRecovery::Restored => ConnectionStatus::IceRestored,
+                IceRecovery::Failed => ConnectionStatus::IceFailed,
+            };
+            inner.emit_status(status, peer_id.as_deref());
+        })
+    }
+
+    // エラーをコンソールに出し、on_error に渡す
+    pub(crate) fn report_error(&self, error: ClientError) {
+        console::error_1(&error.to_string().into());
+        let callback = self.callbacks.borrow().on_error.clone();
+        if let Some(callback) = callback {
+            let _ = callback.call1(&self.socket.get(), &error.into());
+        }
+    }
+
+    // 各 WebRTCConnection のバックグラウンドのエラーを on_error に転送する
+    fn error_handler(self: &Rc<Self>) -> Rc<dyn Fn(ClientError)> {
+        let weak = Rc::downgrade(self);
+        Rc::new(move |error: ClientError| {
+            if let Some(inner) = weak.upgrade() {
+                inner.report_error(error);
+            }
+        })
+    }
+
+    // 1対1の接続
+    pub(crate) fn default_connection(&self) -> WebRTCConnection {
+        self.peerconnection.borrow().clone()
+    }
+
+    // 1対1の接続のイベントをクライアントのコールバックにつなぐ
+    fn configure_default_connection(self: &Rc<Self>, connection: &WebRTCConnection) {
+        connection.set_candidate_error_handler(self.candidate_error_handler());
+        connection.set_data_channel_handler(self.data_channel_handler(None));
+        connection.set_ice_recovery_handler(self.ice_recovery_handler(None));
+        connection.set_error_handler(self.error_handler());
+        if let Err(e) = connection.set_codec_orders(&self.codec_order.borrow()) {
+            self.report_error(e);
+        }
+        connection.store_send_limits(&self.send_limits.borrow());
+        let weak = Rc::downgrade(self);
+        connection.set_track_handler(Rc::new(move |stream: MediaStream| {
+            if let Some(inner) = weak.upgrade() {
+                inner.emit_remote_track(&stream, None);
+            }
+        }));
+    }
+
+    // 1対1の接続を閉じて新しい接続に置き換える (次の通話用)
+    fn reset_default_connection(self: &Rc<Self>) -> Result<(), JsValue> {
+        let sender = SignalingSender::routed(self.socket.clone(), &self.peer_id, None, None);
+        let connection = WebRTCConnection::with_signaling(sender, &self.ice_servers.borrow())?;
+        self.configure_default_connection(&connection);
+        if let Some(stream) = self.local_stream.borrow().as_ref() {
+            connection.add_media_stream(stream)?;
+        }
+        let old = self.peerconnection.replace(connection);
+        old.close();
         Ok(())
     }
 
-    pub fn on_message(&self, callback: js_sys::Function) -> Result<(), JsValue> {
-        // console::log_1(&"WebSocket on message.".into());
-        let self_ws = Arc::new(self.ws.clone());
-        let self_peer_connection = Arc::new(self.peerconnection.clone());
-
-        let closure = Closure::wrap(Box::new( move |event: MessageEvent| {
-            let message = event.data();
-            let ws_clone = Arc::clone(&self_ws);
-            let peer_connection_clone = Arc::clone(&self_peer_connection);
- 
-            console::log_1(&format!("Received message : {:?}", message.as_string()).into());
-            // judge if message is json or not
-            if let Some(text) = message.as_string() {
-                match serde_json::from_str::<serde_json::Value>(&text) {
-                    Ok(json) => {
-                        if json["type"].is_string() {
-                            let sdp_type = json["type"].as_str().unwrap_or("");
-                            match sdp_type {
-                                "offer" => {
-                                    // receive offer
-                                    spawn_local(async move {
-                                        let offer = RtcSessionDescriptionInit::new(RtcSdpType::Offer);
-                                        offer.set_sdp(json["sdp"].as_str().unwrap());
-                                        let connection = Arc::clone(&peer_connection_clone); 
-                                        connection.set_remote_description(&offer).await.unwrap();
-                                        // console::log_1(&"Set offer to peerconnection.".into());
-                
-                                        let answer = connection.create_answer().await.unwrap();
-                                        let rtc_answer: RtcSessionDescriptionInit = answer.clone().unchecked_into();
-                                        connection.set_local_description(&rtc_answer).await.unwrap();
-                                        console::log_1(&format!("Created answer: {:?}", answer).into());
-
-                                        // Update connection status
-                                        if let Some(window) = web_sys::window() {
-                                            if let Some(document) = window.document() {
-                                                if let Some(status_element) = document.get_element_by_id("connectionStatus") {
-                                                    let _ = status_element.set_text_content(Some("Status: Received offer, sent answer"));
-                                                }
-                                            }
-                                        }
-
-                                        let answer_str = js_sys::JSON::stringify(&answer).unwrap_or_else(|_| JsString::from(""));
-                                        if let Some(sdp_str) = answer_str.as_string() {
-                                            let ws = Arc::clone(&ws_clone);
-                                            //self.send_message(&sdp_str);
-                                            ws.send_with_str(&sdp_str).unwrap();
-                                            console::log_1(&format!("Send answer: {:?}", &sdp_str).into());
-                                        } else {
-                                            console::log_1(&JsValue::from_str("Failed to extract SDP"));
-                                        }
-                                    });
-                                }
-                                "answer" => {
-                                    spawn_local(async move {
-                                        let answer = RtcSessionDescriptionInit::new(RtcSdpType::Answer);
-                                        answer.set_sdp(json["sdp"].as_str().unwrap());
-
-                                        let connection = Arc::clone(&peer_connection_clone);
-                                        connection.set_remote_description(&answer).await.unwrap();
-                                        console::log_1(&"Set answer to peerconnection.".into());
-                                        
-                                        // Update connection status
-                                        if let Some(window) = web_sys::window() {
-                                            if let Some(document) = window.document() {
-                                                if let Some(status_element) = document.get_element_by_id("connectionStatus") {
-                                                    let _ = status_element.set_text_content(Some("Status: Received answer, connection established"));
-                                                }
-                                            }
-                                        }
-                                    });
-                                }
-                                "icecandidate" => {
-                                    // ICE Candidate受信時の処理
-                                    if let Some(candidate_val) = json.get("candidate") {
-                                        // candidate_valはserde_json::Valueなので、candidate文字列を直接取得
-                                        let candidate_str = candidate_val["candidate"].as_str().unwrap_or("");
-                                        let mut candidate_obj = RtcIceCandidateInit::new(candidate_str);
-                                        if let Some(sdp_mid) = candidate_val["sdpMid"].as_str() {
-                                            candidate_obj.set_sdp_mid(Some(sdp_mid));
-                                        }
-                                        if let Some(sdp_mline_index) = candidate_val["sdpMLineIndex"].as_u64() {
-                                            candidate_obj.set_sdp_m_line_index(Some(sdp_mline_index as u16));
-                                        }
-                                        let connection = Arc::clone(&peer_connection_clone);
-                                        wasm_bindgen_futures::spawn_local(async move {
-                                            if let Err(e) = connection.add_ice_candidate(&candidate_obj).await {
-                                                web_sys::console::error_1(&e);
-                                            } else {
-                                                web_sys::console::log_1(&"ICE candidate added".into());
-                                            }
-                                        });
-                                    }
-                                },
-                                _ => {
-                                    console::log_1(&JsValue::from_str("Unknown SDP type"));
-                                }
+    // 相手から bye を受け取った。カメラはそのままで、次の通話を受けられるようにする
+    pub(crate) fn end_call(self: &Rc<Self>, peer_id: Option<&str>) {
+        console::log_1(&"Remote peer said bye".into());
+        self.end_screen_share();
+        self.cancel_all_files();
+        if let Err(e) = self.reset_default_connection() {
+            self.report_error(ClientError::negotiation(e));
+        }
+        // 自分のカメラは通話後も使えるのでローカルの映像はそのまま
+        self.dom.borrow().clear_remote_stream();
+        self.emit_status(ConnectionStatus::CallEnded, peer_id);
+    }
+
+    fn stop_media(&self) {
+        self.muted.replace(MuteState::default());
+        if let Some(stream) = self.local_stream.take() {
+            for track in stream.get_tracks() {
+                if let Ok(track) = track.dyn_into::<web_sys::MediaStreamTrack>() {
+                    track.stop();
+                }
+            }
+        }
+    }
+
+    fn hang_up(self: &Rc<Self>) -> Result<(), ClientError> {
+        self.cancel_all_files();
+        self.end_screen_share();
+        let connection = self.default_connection();
+        let bye = if connection.has_remote() {
+            connection.send_signal(SignalingMessage::Bye)
+        } else {
+            Ok(())
+        };
+        let leave = self.leave_room();
+        self.stop_media();
+        // destroy 中は次の通話用の接続を作らない (destroy が今の接続を閉じる)
+        if !self.destroyed.get() {
+            self.reset_default_connection().map_err(ClientError::negotiation)?;
+        }
+        self.dom.borrow().clear_streams();
+        self.emit_status(ConnectionStatus::CallEnded, None);
+        bye.and(leave)
+    }
+
+    fn destroy(self: &Rc<Self>) {
+        if self.destroyed.replace(true) {
+            return;
+        }
+        if let Err(e) = self.hang_up() {
+            self.report_error(e);
+        }
+        self.default_connection().close();
+        self.closed_by_user.set(true);
+        self.stop_heartbeat();
+        let ws = self.socket.get();
+        ClientInner::detach(&ws);
+        let _ = ws.close();
+        self.socket.set_on_overflow(None);
+        self.callbacks.replace(Callbacks::default());
+        self.dom.replace(DomBinding::default());
+        // destroy がソケットのコールバックの中から呼ばれても良いように、解放は後で行う
+        let closures = self.closures.take();
+        spawn_local(async move {
+            drop(closures);
+        });
+    }
+
+    // peerId に対応する接続 (None なら1対1の接続)
+    fn connection_for(&self, peer_id: Option<&str>) -> Option<WebRTCConnection> {
+        match peer_id {
+            None => Some(self.default_connection()),
+            Some(peer_id) => self.room.borrow().peers.get(peer_id).cloned(),
+        }
+    }
+
+    fn connections(&self) -> Vec<WebRTCConnection> {
+        let mut connections = vec![self.default_connection()];
+        connections.extend(self.room.borrow().peers.values().cloned());
+        connections
+    }
+
+    // カメラの映像を全ての接続に追加する
+    fn set_local_stream(&self, stream: MediaStream) {
+        for connection in self.connections() {
+            if let Err(e) = connection.add_media_stream(&stream) {
+                self.report_error(ClientError::media(e));
+            }
+        }
+        self.local_stream.replace(Some(stream));
+    }
+
+    // シグナリングメッセージ (offer / answer / icecandidate / bye) の処理
+    pub(crate) fn handle_signaling(self: &Rc<Self>, connection: WebRTCConnection, signal: SignalingMessage) {
+        console::log_1(&format!("Received {} signaling message", signal.kind()).into());
+        let weak = Rc::downgrade(self);
+        let peer_id = connection.remote_peer_id();
+        match signal {
+            SignalingMessage::Offer { sdp } => {
+                // receive offer
+                spawn_local(async move {
+                    let result = connection.handle_description(RtcSdpType::Offer, &sdp).await;
+                    let Some(inner) = weak.upgrade() else {
+                        return;
+                    };
+                    match result {
+                        Ok(RemoteDescription::Applied(answer)) => {
+                            inner.emit_status(ConnectionStatus::OfferReceived, peer_id.as_deref());
+                            // answer() が待つのは1対1の接続のアンサーだけ
+                            if let (Some(answer), None) = (answer, peer_id.as_deref()) {
+                                inner.announce_mute_state(&connection);
+                                inner.answer_waiters.settle(Ok(answer.into()));
                             }
                         }
-                    },
-                    Err(_) => {
-                        console::log_1(&format!("Received non-JSON message: {}", text).into());
-                        let _ = callback.call1(&ws_clone, &message);
+                        Ok(RemoteDescription::Ignored) => {}
+                        Err(e) => {
+                            if peer_id.is_none() {
+                                inner.answer_waiters.settle(Err(e.clone()));
+                            }
+                            inner.report_error(e);
+                        }
                     }
+                });
+            }
+            SignalingMessage::Answer { sdp } => {
+                spawn_local(async move {
+                    match connection.handle_description(RtcSdpType::Answer, &sdp).await {
+                        Ok(_) => {
+                            console::log_1(&"Set answer to peerconnection.".into());
+                            if let Some(inner) = weak.upgrade() {
+                                inner.emit_status(ConnectionStatus::AnswerReceived, peer_id.as_deref());
+                            }
+                        }
+                        Err(e) => {
+                            if let Some(inner) = weak.upgrade() {
+                                inner.report_error(e);
+                            }
+                        }
+                    }
+                });
+            }
+            SignalingMessage::IceCandidate { candidate } => {
+                // ICE Candidate受信時の処理
+                spawn_local(async move {
+                    connection.handle_remote_candidate(candidate).await;
+                });
+            }
+            // bye は route_envelope で処理済み
+            SignalingMessage::Bye => {}
+            SignalingMessage::Mute { kind, muted } => self.handle_remote_mute(kind, muted, peer_id.as_deref()),
+            SignalingMessage::Join { .. } | SignalingMessage::Leave { .. } | SignalingMessage::App { .. } => {}
+        }
+    }
+
+    fn emit_local_stream(&self, stream: &MediaStream) {
+        self.dom.borrow().show_local_stream(stream);
+        let callback = self.callbacks.borrow().on_local_stream.clone();
+        if let Some(callback) = callback {
+            let _ = callback.call1(&JsValue::NULL, stream);
+        }
+        self.emit_status(ConnectionStatus::LocalMediaStarted, None);
+    }
+
+    pub(crate) fn emit_remote_track(&self, stream: &MediaStream, peer_id: Option<&str>) {
+        self.dom.borrow().show_remote_stream(stream);
+        let callback = self.callbacks.borrow().on_remote_track.clone();
+        if let Some(callback) = callback {
+            let _ = callback.call2(&JsValue::NULL, stream, &peer_id.map_or(JsValue::NULL, JsValue::from_str));
+        }
+        self.emit_status(ConnectionStatus::RemoteTrackReceived, peer_id);
+    }
+
+    pub(crate) fn emit_status(&self, status: ConnectionStatus, peer_id: Option<&str>) {
+        self.dom.borrow().show_status(status);
+        let callback = self.callbacks.borrow().on_status_change.clone();
+        if let Some(callback) = callback {
+            let peer_id = peer_id.map_or(JsValue::NULL, JsValue::from_str);
+            let _ = callback.call2(&JsValue::NULL, &JsValue::from_str(status.as_str()), &peer_id);
+        }
+    }
+
+    // ソケットにイベントハンドラーを登録
+    fn attach(&self, ws: &WebSocket) {
+        if let Some(closures) = self.closures.borrow().as_ref() {
+            ws.set_onopen(Some(closures.on_open.as_ref().unchecked_ref()));
+            ws.set_onmessage(Some(closures.on_message.as_ref().unchecked_ref()));
+            ws.set_onerror(Some(closures.on_error.as_ref().unchecked_ref()));
+            ws.set_onclose(Some(closures.on_close.as_ref().unchecked_ref()));
+        }
+    }
+
+    fn detach(ws: &WebSocket) {
+        ws.set_onopen(None);
+        ws.set_onmessage(None);
+        ws.set_onerror(None);
+        ws.set_onclose(None);
+    }
+
+    fn handle_open(&self) {
+        let ws = self.socket.get();
+        let attempt = self.attempt.replace(0);
+        // コールバック内から on_xxx が呼ばれても良いように借用を解放してから呼ぶ
+        let (on_reconnected, on_open) = {
+            let callbacks = self.callbacks.borrow();
+            (callbacks.on_reconnected.clone(), callbacks.on_open.clone())
+        };
+        // 接続待ちの間に溜まったメッセージを送信
+        if let Err(e) = self.socket.flush() {
+            self.report_error(ClientError::transport(e));
+        }
+        self.open_waiters.settle(Ok(JsValue::UNDEFINED));
+        if attempt > 0 {
+            // サーバー側のルーム情報は失われているので参加し直す
+            if let Err(e) = self.rejoin_room() {
+                self.report_error(ClientError::signaling(e));
+            }
+            console::log_1(&format!("WebSocket reconnected after {} attempt(s)", attempt).into());
+            if let Some(callback) = on_reconnected {
+                let _ = callback.call1(&ws, &JsValue::from(attempt));
+            }
+        }
+        if let Some(callback) = on_open {
+            let _ = callback.call0(&ws);
+        }
+        self.emit_status(ConnectionStatus::SignalingConnected, None);
+        self.start_heartbeat();
+    }
+
+    fn handle_message(self: Rc<Self>, event: MessageEvent) {
+        let ws = self.socket.get();
+        let message = event.data();
+        if let Some(buffer) = message.dyn_ref::<js_sys::ArrayBuffer>() {
+            self.heartbeat.borrow_mut().on_received(&HeartbeatFrame::Other);
+            self.handle_binary(&ws, js_sys::Uint8Array::new(buffer));
+            return;
+        }
+        let callback = self.callbacks.borrow().on_message.clone();
+
+        let Some(text) = message.as_string() else {
+            console::log_1(&"Received unsupported message type".into());
+            return;
+        };
+
+        // ハートビートの ping / pong はアプリに渡さない
+        if self.handle_heartbeat_frame(&text) {
+            return;
+        }
+        console::log_1(&format!("Received message : {:?}", text).into());
+
+        // judge if message is signaling json or not
+        match Envelope::decode(&text) {
+            Ok(Envelope { message: SignalingMessage::App { kind, .. }, .. }) => {
+                console::log_1(&format!("Received app message: {}", kind).into());
+                if let Some(callback) = callback {
+                    let _ = callback.call1(&ws, &message);
                 }
-            } else {
-                println!("Received non-text message");
             }
-        }) as Box<dyn Fn(MessageEvent)>);
+            Ok(envelope) => self.route_envelope(envelope),
+            Err(SignalingError::NotJson(_)) => {
+                console::log_1(&format!("Received non-JSON message: {}", text).into());
+                if let Some(callback) = callback {
+                    let _ = callback.call1(&ws, &message);
+                }
+            }
+            Err(e) => self.report_error(ClientError::Protocol(format!("dropped signaling message: {}", e))),
+        }
+    }
 
-        self.ws.set_onmessage(Some(closure.as_ref().unchecked_ref()));
-        closure.forget();
-        Ok(())
+    fn handle_binary(&self, ws: &WebSocket, data: js_sys::Uint8Array) {
+        console::log_1(&format!("Received binary message: {} bytes", data.length()).into());
+        let (on_binary, binary_handler) = {
+            let callbacks = self.callbacks.borrow();
+            (callbacks.on_binary.clone(), callbacks.binary_handler.clone())
+        };
+        if let Some(handler) = binary_handler {
+            handler(data.to_vec());
+        }
+        if let Some(callback) = on_binary {
+            let _ = callback.call1(ws, &data);
+        }
     }
 
-    pub fn on_error(&self, callback: js_sys::Function) -> Result<(), JsValue> {
-        let cloned_ws = self.ws.clone();
-        let closure = Closure::wrap(Box::new(move |event: ErrorEvent| {
-            let _ = callback.call1(&cloned_ws, &event);
-        }) as Box<dyn Fn(ErrorEvent)>);
+    fn handle_close(self: Rc<Self>, event: CloseEvent) {
+        console::log_1(&format!("WebSocket closed: code={} clean={}", event.code(), event.was_clean()).into());
+        self.stop_heartbeat();
+        let kind = CloseKind::from_code(event.code());
+        let should_reconnect = !self.closed_by_user.get() && kind.should_reconnect() && self.reconnect.enabled;
+        // maxAttempts に達していれば再接続しない
+        let will_reconnect = should_reconnect && self.reconnect.delay_for(self.attempt.get() + 1, 0.0).is_some();
+        let info = CloseInfo {
+            code: event.code(),
+            reason: event.reason(),
+            was_clean: event.was_clean(),
+            kind,
+            will_reconnect,
+        };
 
-        self.ws.set_onerror(Some(closure.as_ref().unchecked_ref()));
-        closure.forget();
-        Ok(())
+        self.open_waiters.settle(Err(ClientError::Transport(format!("WebSocket closed with code {}", info.code))));
+        self.emit_status(ConnectionStatus::SignalingDisconnected, None);
+        let on_close = self.callbacks.borrow().on_close.clone();
+        if let Some(callback) = on_close {
+            match serde_wasm_bindgen::to_value(&info) {
+                Ok(value) => {
+                    let _ = callback.call1(&self.socket.get(), &value);
+                }
+                Err(e) => console::error_1(&e.into()),
+            }
+        }
+
+        if should_reconnect {
+            self.schedule_reconnect();
+        }
     }
 
-    pub fn close(&self) -> Result<(), JsValue> {
-        self.ws.close()
+    fn schedule_reconnect(self: Rc<Self>) {
+        let attempt = self.attempt.get() + 1;
+        let Some(delay) = self.reconnect.delay_for(attempt, js_sys::Math::random()) else {
+            self.report_error(ClientError::Transport(format!("WebSocket reconnect gave up after {} attempt(s)", attempt - 1)));
+            return;
+        };
+        self.attempt.set(attempt);
+        console::log_1(&format!("WebSocket reconnecting in {} ms (attempt {})", delay, attempt).into());
+
+        let on_reconnecting = self.callbacks.borrow().on_reconnecting.clone();
+        if let Some(callback) = on_reconnecting {
+            let _ = callback.call2(&JsValue::NULL, &JsValue::from(attempt), &JsValue::from(delay));
+        }
+
+        let weak = Rc::downgrade(&self);
+        let timer = Closure::once_into_js(move || {
+            if let Some(inner) = weak.upgrade() {
+                inner.reconnect_now();
+            }
+        });
+        match set_timeout(&timer, delay) {
+            Ok(_) => self.reconnect_pending.set(true),
+            Err(e) => console::error_1(&e),
+        }
     }
 
-    pub async fn offer(&mut self) -> () {
-        let offer = self.peerconnection.create_offer().await.unwrap();
-        let rtc_offer: RtcSessionDescriptionInit = offer.clone().unchecked_into();
+    // ping / pong なら処理して true を返す
+    fn handle_heartbeat_frame(&self, text: &str) -> bool {
+        let (frame, reply) = {
+            let mut heartbeat = self.heartbeat.borrow_mut();
+            if !heartbeat.options().enabled {
+                return false;
+            }
+            let frame = heartbeat.classify(text);
+            heartbeat.on_received(&frame);
+            let options = heartbeat.options();
+            let reply = options.reply_to_ping.then(|| options.pong.clone());
+            (frame, reply)
+        };
+        match frame {
+            HeartbeatFrame::Pong => true,
+            HeartbeatFrame::Ping => {
+                if let Some(pong) = reply {
+                    if let Err(e) = self.socket.get().send_with_str(&pong) {
+                        console::error_1(&e);
+                    }
+                }
+                true
+            }
+            HeartbeatFrame::Other => false,
+        }
+    }
 
-        self.peerconnection.set_local_description(&rtc_offer).await.unwrap();
+    fn start_heartbeat(&self) {
+        self.stop_heartbeat();
+        let interval_ms = {
+            let heartbeat = self.heartbeat.borrow();
+            if !heartbeat.options().enabled {
+                return;
+            }
+            heartbeat.options().interval_ms
+        };
+        let closures = self.closures.borrow();
+        let Some(closures) = closures.as_ref() else {
+            return;
+        };
+        let timer = web_sys::window().ok_or_else(|| JsValue::from_str("no window")).and_then(|window| {
+            window.set_interval_with_callback_and_timeout_and_arguments_0(
+                closures.heartbeat_tick.as_ref().unchecked_ref(),
+                interval_ms.min(i32::MAX as u32) as i32,
+            )
+        });
+        match timer {
+            Ok(id) => self.heartbeat_timer.set(Some(id)),
+            Err(e) => console::error_1(&e),
+        }
+    }
 
-        let offer_str = js_sys::JSON::stringify(&offer).unwrap_or_else(|_| JsString::from(""));
-        if let Some(sdp_str) = offer_str.as_string() {
-            self.send_message(&sdp_str);
-            //self.ws.send_with_str(&sdp_str).unwrap();
-        } else {
-            console::log_1(&JsValue::from_str("Failed to extract SDP"));
+    fn stop_heartbeat(&self) {
+        if let Some(id) = self.heartbeat_timer.take() {
+            if let Some(window) = web_sys::window() {
+                window.clear_interval_with_handle(id);
+            }
+        }
+        self.heartbeat.borrow_mut().reset();
+    }
+
+    fn heartbeat_tick(self: Rc<Self>) {
+        let ws = self.socket.get();
+        if ws.ready_state() != WebSocket::OPEN {
+            return;
+        }
+        let (ping_id, ping, timeout_ms) = {
+            let mut heartbeat = self.heartbeat.borrow_mut();
+            let Some(ping_id) = heartbeat.on_tick() else {
+                return;
+            };
+            (ping_id, heartbeat.options().ping.clone(), heartbeat.options().timeout_ms)
+        };
+        if let Err(e) = ws.send_with_str(&ping) {
+            console::error_1(&e);
+        }
+
+        let weak = Rc::downgrade(&self);
+        let timeout = Closure::once_into_js(move || {
+            if let Some(inner) = weak.upgrade() {
+                if inner.heartbeat.borrow().is_lost(ping_id) {
+                    inner.handle_connection_lost();
+                }
+            }
+        });
+        if let Err(e) = set_timeout(&timeout, timeout_ms) {
+            console::error_1(&e);
+        }
+    }
+
+    // ハーフオープンになったソケットを捨てて再接続する
+    fn handle_connection_lost(self: Rc<Self>) {
+        console::error_1(&"WebSocket heartbeat timed out, connection lost".into());
+        self.stop_heartbeat();
+
+        let on_connection_lost = self.callbacks.borrow().on_connection_lost.clone();
+        if let Some(callback) = on_connection_lost {
+            let _ = callback.call0(&self.socket.get());
+        }
+
+        // 古いソケットの close イベントは届くまで時間がかかるので待たない
+        let ws = self.socket.get();
+        ClientInner::detach(&ws);
+        let _ = ws.close_with_code_and_reason(4000, "heartbeat timeout");
+
+        if !self.closed_by_user.get() && self.reconnect.enabled {
+            self.schedule_reconnect();
         }
     }
 
-}
\ No newline at end of file
+    fn reconnect_now(self: Rc<Self>) {
+        self.reconnect_pending.set(false);
+        if self.closed_by_user.get() {
+            return;
+        }
+        match open_socket(&self.url) {
+            Ok(ws) => {
+                self.attach(&ws);
+                let old = self.socket.replace(ws);
+                ClientInner::detach(&old);
+            }
+            Err(e) => {
+                self.report_error(ClientError::transport(e));
+                self.schedule_reconnect();
+            }
+        }
+    }
+}
+
+fn set_timeout(callback: &JsValue, delay_ms: u32) -> Result<i32, JsValue> {
+    let window = web_sys::window().ok_or_else(|| JsValue::from_str("no window"))?;
+    window.set_timeout_with_callback_and_timeout_and_arguments_0(
+        callback.unchecked_ref(),
+        delay_ms.min(i32::MAX as u32) as i32,
+    )
+}
+
+fn generate_peer_id() -> String {
+    let random = (js_sys::Math::random() * u32::MAX as f64) as u32;
+    format!("peer-{:08x}{:04x}", random, (js_sys::Date::now() as u64) & 0xffff)
+}
+
+fn open_socket(url: &str) -> Result<WebSocket, JsValue> {
+    let ws = WebSocket::new(url)?;
+    // Set binary type to arraybuffer
+    ws.set_binary_type(web_sys::BinaryType::Arraybuffer);
+    Ok(ws)
+}
@@ -0,0 +1,220 @@
+use js_sys::{Object, Reflect};
+use serde::{Deserialize, Serialize};
+use wasm_bindgen::prelude::*;
+use wasm_bindgen_futures::JsFuture;
+use web_sys::{MediaDeviceInfo, MediaDeviceKind, MediaStream, MediaStreamConstraints, MediaStreamTrack};
+
+// start_media に渡すキャプチャ設定
+// width / height / frameRate / facingMode は ideal、deviceId は exact として getUserMedia に渡す
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(default, rename_all = "camelCase")]
+pub struct MediaOptions {
+    pub audio: bool,
+    pub video: bool,
+    pub width: Option<u32>,
+    pub height: Option<u32>,
+    pub frame_rate: Option<f64>,
+    // "user" (前面カメラ) / "environment" (背面カメラ) など
+    pub facing_mode: Option<String>,
+    // カメラのデバイスID
+    pub device_id: Option<String>,
+    // マイクのデバイスID
+    pub audio_device_id: Option<String>,
+}
+
+impl Default for MediaOptions {
+    fn default() -> Self {
+        MediaOptions {
+            audio: true,
+            video: true,
+            width: None,
+            height: None,
+            frame_rate: None,
+            facing_mode: None,
+            device_id: None,
+            audio_device_id: None,
+        }
+    }
+}
+
+impl MediaOptions {
+    // undefined / null の場合は音声と映像の両方をキャプチャする
+    pub fn from_js(value: JsValue) -> Result<MediaOptions, JsValue> {
+        let options: MediaOptions = if value.is_undefined() || value.is_null() {
+            MediaOptions::default()
+        } else {
+            serde_wasm_bindgen::from_value(value)?
+        };
+        options.validate().map_err(|e| JsValue::from_str(&e))?;
+        Ok(options)
+    }
+
+    // 指定したデバイスだけをキャプチャする設定 (kind は "audio" または "video")
+    pub fn for_device(kind: &str, device_id: &str) -> Result<MediaOptions, String> {
+        let device_id = Some(device_id.to_string());
+        let options = match kind {
+            "audio" => MediaOptions { video: false, audio_device_id: device_id, ..MediaOptions::default() },
+            "video" => MediaOptions { audio: false, device_id, ..MediaOptions::default() },
+            other => return Err(format!("unknown media kind: {}", other)),
+        };
+        options.validate()?;
+        Ok(options)
+    }
+
+    pub fn validate(&self) -> Result<(), String> {
+        if !self.audio && !self.video {
+            return Err("at least one of audio or video must be enabled".to_string());
+        }
+        if self.width == Some(0) || self.height == Some(0) {
+            return Err("width and height must be greater than 0".to_string());
+        }
+        if self.frame_rate.is_some_and(|rate| rate.is_nan() || rate <= 0.0) {
+            return Err("frameRate must be greater than 0".to_string());
+        }
+        if let Some(mode) = &self.facing_mode {
+            if !matches!(mode.as_str(), "user" | "environment" | "left" | "right") {
+                return Err(format!("unknown facingMode: {}", mode));
+            }
+        }
+        if self.device_id.as_deref().is_some_and(str::is_empty)
+            || self.audio_device_id.as_deref().is_some_and(str::is_empty)
+        {
+            return Err("deviceId must not be empty".to_string());
+        }
+        let has_video_settings = self.width.is_some()
+            || self.height.is_some()
+            || self.frame_rate.is_some()
+            || self.facing_mode.is_some()
+            || self.device_id.is_some();
+        if !self.video && has_video_settings {
+            return Err("video settings were given but video is disabled".to_string());
+        }
+        if !self.audio && self.audio_device_id.is_some() {
+            return Err("audioDeviceId was given but audio is disabled".to_string());
+        }
+        Ok(())
+    }
+
+    fn video_constraints(&self) -> Result<JsValue, JsValue> {
+        if !self.video {
+            return Ok(JsValue::FALSE);
+        }
+        let track = Object::new();
+        if let Some(width) = self.width {
+            set_constraint(&track, "width", "ideal", &width.into())?;
+        }
+        if let Some(height) = self.height {
+            set_constraint(&track, "height", "ideal", &height.into())?;
+        }
+        if let Some(rate) = self.frame_rate {
+            set_constraint(&track, "frameRate", "ideal", &rate.into())?;
+        }
+        if let Some(mode) = &self.facing_mode {
+            set_constraint(&track, "facingMode", "ideal", &mode.into())?;
+        }
+        if let Some(device_id) = &self.device_id {
+            set_constraint(&track, "deviceId", "exact", &device_id.into())?;
+        }
+        Ok(if Object::keys(&track).length() == 0 { JsValue::TRUE } else { track.into() })
+    }
+
+    fn audio_constraints(&self) -> Result<JsValue, JsValue> {
+        if !self.audio {
+            return Ok(JsValue::FALSE);
+        }
+        let Some(device_id) = &self.audio_device_id else {
+            return Ok(JsValue::TRUE);
+        };
+        let track = Object::new();
+        set_constraint(&track, "deviceId", "exact", &device_id.into())?;
+        Ok(track.into())
+    }
+
+    pub fn to_constraints(&self) -> Result<MediaStreamConstraints, JsValue> {
+        let constraints = MediaStreamConstraints::new();
+        constraints.set_video(&self.video_constraints()?);
+        constraints.set_audio(&self.audio_constraints()?);
+        Ok(constraints)
+    }
+}
+
+// { name: { kind: value } } の形で制約を追加する
+fn set_constraint(track: &Object, name: &str, kind: &str, value: &JsValue) -> Result<(), JsValue> {
+    let constraint = Object::new();
+    Reflect::set(&constraint, &kind.into(), value)?;
+    Reflect::set(track, &name.into(), &constraint)?;
+    Ok(())
+}
+
+// getUserMedia でカメラ・マイクを取得する
+pub async fn get_user_media(options: &MediaOptions) -> Result<MediaStream, JsValue> {
+    let window = web_sys::window().ok_or_else(|| JsValue::from_str("no window"))?;
+    let media_devices = window.navigator().media_devices()?;
+    let promise = media_devices.get_user_media_with_constraints(&options.to_constraints()?)?;
+    let stream = JsFuture::from(promise).await?;
+    stream.dyn_into::<MediaStream>()
+}
+
+// 止めたトラックを同じ制約 (デバイス・解像度など) で取り直す
+pub async fn recapture(track: &MediaStreamTrack) -> Result<MediaStreamTrack, JsValue> {
+    let window = web_sys::window().ok_or_else(|| JsValue::from_str("no window"))?;
+    let media_devices = window.navigator().media_devices()?;
+    let constraints = MediaStreamConstraints::new();
+    let track_constraints: JsValue = track.get_constraints().into();
+    if track.kind() == "audio" {
+        constraints.set_audio(&track_constraints);
+    } else {
+        constraints.set_video(&track_constraints);
+    }
+    let promise = media_devices.get_user_media_with_constraints(&constraints)?;
+    let stream: MediaStream = JsFuture::from(promise).await?.dyn_into()?;
+    stream
+        .get_tracks()
+        .iter()
+        .find_map(|track| track.dyn_into::<MediaStreamTrack>().ok())
+        .ok_or_else(|| JsValue::from_str("no track was captured"))
+}
+
+// list_devices が返す入力デバイス
+#[derive(Debug, Clone, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct InputDevice {
+    pub device_id: String,
+    pub group_id: String,
+    // "audio" または "video"
+    pub kind: &'static str,
+    // 権限が無い間はブラウザが空文字を返す
+    pub label: String,
+}
+
+// カメラ・マイクの一覧を取得する (スピーカーなどの出力デバイスは除く)
+pub async fn enumerate_input_devices() -> Result<Vec<InputDevice>, JsValue> {
+    let window = web_sys::window().ok_or_else(|| JsValue::from_str("no window"))?;
+    let media_devices = window.navigator().media_devices()?;
+    let devices: js_sys::Array = JsFuture::from(media_devices.enumerate_devices()?).await?.dyn_into()?;
+
+    let mut inputs = Vec::new();
+    for device in devices.iter() {
+        let device: MediaDeviceInfo = device.dyn_into()?;
+        let kind = match device.kind() {
+            MediaDeviceKind::Audioinput => "audio",
+            MediaDeviceKind::Videoinput => "video",
+            _ => continue,
+        };
+        inputs.push(InputDevice {
+            device_id: device.device_id(),
+            group_id: device.group_id(),
+            kind,
+            label: device.label(),
+        });
+    }
+    Ok(inputs)
+}
+
+// getDisplayMedia で画面をキャプチャする
+pub async fn get_display_media() -> Result<MediaStream, JsValue> {
+    let window = web_sys::window().ok_or_else(|| JsValue::from_str("no window"))?;
+    let media_devices = window.navigator().media_devices()?;
+    let stream = JsFuture::from(media_devices.get_display_media()?).await?;
+    stream.dyn_into::<MediaStream>()
+}
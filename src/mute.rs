@@ -0,0 +1,113 @@
+use wasm_bindgen::prelude::*;
+use web_sys::{console, MediaStreamTrack, MediaStreamTrackState};
+use crate::error::ClientError;
+use crate::media;
+use crate::signaling::{SignalingMessage, TrackKind};
+use crate::webrtc_peer_connection::WebRTCConnection;
+use crate::ClientInner;
+
+// 自分のマイク・カメラをオフにしているか
+#[derive(Debug, Clone, Copy, Default)]
+pub(crate) struct MuteState {
+    pub audio: bool,
+    pub video: bool,
+}
+
+impl MuteState {
+    fn get(self, kind: TrackKind) -> bool {
+        match kind {
+            TrackKind::Audio => self.audio,
+            TrackKind::Video => self.video,
+        }
+    }
+
+    fn set(&mut self, kind: TrackKind, muted: bool) {
+        match kind {
+            TrackKind::Audio => self.audio = muted,
+            TrackKind::Video => self.video = muted,
+        }
+    }
+}
+
+impl ClientInner {
+    // ローカルのトラックを有効・無効にして相手に mute を送る
+    // stop_capture ならトラックを止めてデバイスを解放し、有効に戻すときに同じ制約で取り直す
+    pub(crate) async fn set_track_enabled(&self, kind: TrackKind, enabled: bool, stop_capture: bool) -> Result<(), ClientError> {
+        let Some(stream) = self.local_stream.borrow().clone() else {
+            return Err(ClientError::Media("media not started; call start_media first".to_string()));
+        };
+        let tracks: Vec<MediaStreamTrack> = stream
+            .get_tracks()
+            .iter()
+            .filter_map(|track| track.dyn_into::<MediaStreamTrack>().ok())
+            .filter(|track| track.kind() == kind.as_str())
+            .collect();
+        if tracks.is_empty() {
+            return Err(ClientError::Media(format!("no local {} track", kind.as_str())));
+        }
+
+        for track in tracks {
+            if !enabled {
+                track.set_enabled(false);
+                if stop_capture {
+                    track.stop();
+                }
+                continue;
+            }
+            if track.ready_state() != MediaStreamTrackState::Ended {
+                track.set_enabled(true);
+                continue;
+            }
+            let fresh = media::recapture(&track).await.map_err(ClientError::media)?;
+            // 画面共有中は映像の送信トラックを差し替えない (共有終了時にカメラへ戻る)
+            let sharing_screen = kind == TrackKind::Video && self.screen_share.borrow().is_some();
+            if !sharing_screen {
+                for connection in self.connections() {
+                    if let Err(e) = connection.replace_track(fresh.clone()).await {
+                        self.report_error(ClientError::media(e));
+                    }
+                }
+            }
+            stream.remove_track(&track);
+            stream.add_track(&fresh);
+        }
+
+        self.muted.borrow_mut().set(kind, !enabled);
+        for connection in self.connections() {
+            if connection.has_remote() {
+                self.send_mute(&connection, kind, !enabled);
+            }
+        }
+        Ok(())
+    }
+
+    pub(crate) fn is_track_enabled(&self, kind: TrackKind) -> bool {
+        !self.muted.borrow().get(kind)
+    }
+
+    // 通話を始めた相手にオフにしているトラックを知らせる
+    pub(crate) fn announce_mute_state(&self, connection: &WebRTCConnection) {
+        let muted = *self.muted.borrow();
+        for kind in [TrackKind::Audio, TrackKind::Video] {
+            if muted.get(kind) {
+                self.send_mute(connection, kind, true);
+            }
+        }
+    }
+
+    fn send_mute(&self, connection: &WebRTCConnection, kind: TrackKind, muted: bool) {
+        if let Err(e) = connection.send_signal(SignalingMessage::Mute { kind, muted }) {
+            self.report_error(e);
+        }
+    }
+
+    // 相手から mute を受け取ったら on_remote_mute に (kind, muted, peerId) で知らせる
+    pub(crate) fn handle_remote_mute(&self, kind: TrackKind, muted: bool, peer_id: Option<&str>) {
+        console::log_1(&format!("Remote {} {}", kind.as_str(), if muted { "muted" } else { "unmuted" }).into());
+        let callback = self.callbacks.borrow().on_remote_mute.clone();
+        if let Some(callback) = callback {
+            let peer_id = peer_id.map_or(JsValue::NULL, JsValue::from_str);
+            let _ = callback.call3(&JsValue::NULL, &JsValue::from_str(kind.as_str()), &JsValue::from(muted), &peer_id);
+        }
+    }
+}
@@ -0,0 +1,41 @@
+use serde::Deserialize;
+use wasm_bindgen::JsValue;
+use crate::heartbeat::HeartbeatOptions;
+use crate::ice_servers::IceServerList;
+use crate::outbound_queue::QueueOptions;
+use crate::reconnect::ReconnectPolicy;
+
+// WebSocketClient のコンストラクタに渡すオプション
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default, rename_all = "camelCase")]
+pub struct ClientOptions {
+    // シグナリング上のピアID (省略時はランダムに生成)
+    pub peer_id: Option<String>,
+    pub reconnect: ReconnectPolicy,
+    pub queue: QueueOptions,
+    pub heartbeat: HeartbeatOptions,
+    pub ice_servers: IceServerList,
+}
+
+impl ClientOptions {
+    // undefined / null の場合はデフォルト設定を使う
+    pub fn from_js(value: JsValue) -> Result<ClientOptions, JsValue> {
+        let options: ClientOptions = if value.is_undefined() || value.is_null() {
+            ClientOptions::default()
+        } else {
+            serde_wasm_bindgen::from_value(value)?
+        };
+        options.validate().map_err(|e| JsValue::from_str(&e))?;
+        Ok(options)
+    }
+
+    pub fn validate(&self) -> Result<(), String> {
+        if self.peer_id.as_deref().is_some_and(str::is_empty) {
+            return Err("peerId must not be empty".to_string());
+        }
+        self.reconnect.validate()?;
+        self.queue.validate()?;
+        self.heartbeat.validate()?;
+        self.ice_servers.validate()
+    }
+}
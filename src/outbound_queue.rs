@@ -0,0 +1,169 @@
+use serde::Deserialize;
+use std::collections::VecDeque;
+
+// キューが満杯のときの動作
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub enum OverflowPolicy {
+    // 一番古いメッセージを捨てて新しいメッセージを入れる
+    DropOldest,
+    // 新しいメッセージを拒否し、送信側にエラーを返す
+    Reject,
+    // 新しいメッセージを捨てて on_queue_overflow コールバックに渡す
+    Callback,
+}
+
+// JSからは { capacity, overflow: "dropOldest" | "reject" | "callback" } で指定する
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(default, rename_all = "camelCase")]
+pub struct QueueOptions {
+    pub capacity: usize,
+    pub overflow: OverflowPolicy,
+}
+
+impl Default for QueueOptions {
+    fn default() -> Self {
+        QueueOptions { capacity: 256, overflow: OverflowPolicy::DropOldest }
+    }
+}
+
+impl QueueOptions {
+    pub fn validate(&self) -> Result<(), String> {
+        if self.capacity == 0 {
+            return Err("queue.capacity must be greater than 0".to_string());
+        }
+        Ok(())
+    }
+}
+
+// キューに入れる送信メッセージ (テキストまたはバイナリ)
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum OutboundMessage {
+    Text(String),
+    Binary(Vec<u8>),
+}
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum PushOutcome {
+    Queued,
+    // DropOldest で押し出された古いメッセージ
+    DroppedOldest(OutboundMessage),
+    // Reject / Callback で受け付けられなかった新しいメッセージ
+    Overflowed(OutboundMessage),
+}
+
+// ソケットが OPEN になるまで送信メッセージを順番に保持するキュー
+#[derive(Debug)]
+pub struct OutboundQueue {
+    options: QueueOptions,
+    items: VecDeque<OutboundMessage>,
+}
+
+impl OutboundQueue {
+    pub fn new(options: QueueOptions) -> OutboundQueue {
+        OutboundQueue { options, items: VecDeque::new() }
+    }
+
+    pub fn push(&mut self, message: OutboundMessage) -> PushOutcome {
+        if self.items.len() < self.options.capacity {
+            self.items.push_back(message);
+            return PushOutcome::Queued;
+        }
+        match self.options.overflow {
+            OverflowPolicy::DropOldest => {
+                let dropped = self.items.pop_front();
+                self.items.push_back(message);
+                match dropped {
+                    Some(dropped) => PushOutcome::DroppedOldest(dropped),
+                    None => PushOutcome::Queued,
+                }
+            }
+            OverflowPolicy::Reject | OverflowPolicy::Callback => PushOutcome::Overflowed(message),
+        }
+    }
+
+    pub fn policy(&self) -> OverflowPolicy {
+        self.options.overflow
+    }
+
+    pub fn pop_front(&mut self) -> Option<OutboundMessage> {
+        self.items.pop_front()
+    }
+
+    // 送信に失敗したメッセージを先頭に戻す
+    pub fn push_front(&mut self, message: OutboundMessage) {
+        self.items.push_front(message);
+    }
+
+    pub fn len(&self) -> usize {
+        self.items.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.items.is_empty()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn queue(capacity: usize, overflow: OverflowPolicy) -> OutboundQueue {
+        OutboundQueue::new(QueueOptions { capacity, overflow })
+    }
+
+    fn text(value: &str) -> OutboundMessage {
+        OutboundMessage::Text(value.to_string())
+    }
+
+    fn drain(queue: &mut OutboundQueue) -> Vec<OutboundMessage> {
+        std::iter::from_fn(|| queue.pop_front()).collect()
+    }
+
+    #[test]
+    fn queues_in_order_below_capacity() {
+        let mut queue = queue(3, OverflowPolicy::Reject);
+        assert_eq!(queue.push(text("a")), PushOutcome::Queued);
+        assert_eq!(queue.push(OutboundMessage::Binary(vec![1, 2])), PushOutcome::Queued);
+        assert_eq!(queue.len(), 2);
+        assert_eq!(drain(&mut queue), vec![text("a"), OutboundMessage::Binary(vec![1, 2])]);
+        assert!(queue.is_empty());
+    }
+
+    #[test]
+    fn drop_oldest_evicts_front() {
+        let mut queue = queue(2, OverflowPolicy::DropOldest);
+        queue.push(text("a"));
+        queue.push(text("b"));
+        assert_eq!(queue.push(text("c")), PushOutcome::DroppedOldest(text("a")));
+        assert_eq!(queue.len(), 2);
+        assert_eq!(drain(&mut queue), vec![text("b"), text("c")]);
+    }
+
+    #[test]
+    fn reject_and_callback_return_new_message() {
+        for policy in [OverflowPolicy::Reject, OverflowPolicy::Callback] {
+            let mut queue = queue(1, policy);
+            queue.push(text("a"));
+            assert_eq!(queue.push(text("b")), PushOutcome::Overflowed(text("b")));
+            assert_eq!(queue.policy(), policy);
+            assert_eq!(drain(&mut queue), vec![text("a")]);
+        }
+    }
+
+    #[test]
+    fn push_front_restores_failed_message() {
+        let mut queue = queue(2, OverflowPolicy::Reject);
+        queue.push(text("a"));
+        queue.push(text("b"));
+        let first = queue.pop_front().unwrap();
+        queue.push_front(first);
+        assert_eq!(drain(&mut queue), vec![text("a"), text("b")]);
+    }
+
+    #[test]
+    fn zero_capacity_is_invalid() {
+        assert!(QueueOptions { capacity: 0, overflow: OverflowPolicy::DropOldest }.validate().is_err());
+        assert!(QueueOptions::default().validate().is_ok());
+    }
+}
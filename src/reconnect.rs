@@ -0,0 +1,125 @@
+use serde::Deserialize;
+
+// WebSocket再接続のバックオフ設定
+// JSからは { enabled, initialDelayMs, maxDelayMs, multiplier, jitter, maxAttempts } で指定する
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(default, rename_all = "camelCase")]
+pub struct ReconnectPolicy {
+    pub enabled: bool,
+    pub initial_delay_ms: u32,
+    pub max_delay_ms: u32,
+    pub multiplier: f64,
+    // 0.0 - 1.0: 待ち時間のうちランダムにする割合
+    pub jitter: f64,
+    // None なら無制限に再試行する
+    pub max_attempts: Option<u32>,
+}
+
+impl Default for ReconnectPolicy {
+    fn default() -> Self {
+        ReconnectPolicy {
+            enabled: true,
+            initial_delay_ms: 500,
+            max_delay_ms: 30_000,
+            multiplier: 2.0,
+            jitter: 0.5,
+            max_attempts: Some(10),
+        }
+    }
+}
+
+impl ReconnectPolicy {
+    pub fn validate(&self) -> Result<(), String> {
+        if self.initial_delay_ms == 0 {
+            return Err("reconnect.initialDelayMs must be greater than 0".to_string());
+        }
+        if self.max_delay_ms < self.initial_delay_ms {
+            return Err("reconnect.maxDelayMs must be >= initialDelayMs".to_string());
+        }
+        if !(self.multiplier >= 1.0 && self.multiplier.is_finite()) {
+            return Err("reconnect.multiplier must be a finite number >= 1".to_string());
+        }
+        if !(0.0..=1.0).contains(&self.jitter) {
+            return Err("reconnect.jitter must be between 0 and 1".to_string());
+        }
+        Ok(())
+    }
+
+    // attempt は1始まり。再試行しない場合は None を返す
+    // random は [0, 1) の乱数 (ブラウザでは Math.random())
+    pub fn delay_for(&self, attempt: u32, random: f64) -> Option<u32> {
+        if !self.enabled || attempt == 0 {
+            return None;
+        }
+        if let Some(max_attempts) = self.max_attempts {
+            if attempt > max_attempts {
+                return None;
+            }
+        }
+
+        let exponent = (attempt - 1).min(i32::MAX as u32) as i32;
+        let base = (self.initial_delay_ms as f64 * self.multiplier.powi(exponent))
+            .min(self.max_delay_ms as f64);
+        let random = random.clamp(0.0, 1.0);
+        let delay = base * (1.0 - self.jitter) + base * self.jitter * random;
+        Some(delay.round() as u32)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn policy() -> ReconnectPolicy {
+        ReconnectPolicy { jitter: 0.0, ..ReconnectPolicy::default() }
+    }
+
+    #[test]
+    fn delay_grows_by_multiplier_until_cap() {
+        let policy = ReconnectPolicy { max_attempts: None, ..policy() };
+        let delays: Vec<u32> = (1..=9).map(|attempt| policy.delay_for(attempt, 0.0).unwrap()).collect();
+        assert_eq!(delays, vec![500, 1_000, 2_000, 4_000, 8_000, 16_000, 30_000, 30_000, 30_000]);
+        // 非常に大きな試行回数でも上限で止まる
+        assert_eq!(policy.delay_for(u32::MAX, 0.0), Some(30_000));
+    }
+
+    #[test]
+    fn jitter_stays_within_bounds() {
+        let policy = ReconnectPolicy { jitter: 0.5, ..ReconnectPolicy::default() };
+        // base 2000ms のうち半分がランダム
+        assert_eq!(policy.delay_for(3, 0.0), Some(1_000));
+        assert_eq!(policy.delay_for(3, 0.5), Some(1_500));
+        assert_eq!(policy.delay_for(3, 0.999_999), Some(2_000));
+        for step in 0..=100 {
+            let delay = policy.delay_for(3, step as f64 / 100.0).unwrap();
+            assert!((1_000..=2_000).contains(&delay), "delay {} out of range", delay);
+        }
+        // 範囲外の乱数は丸める
+        assert_eq!(policy.delay_for(3, -1.0), Some(1_000));
+        assert_eq!(policy.delay_for(3, 2.0), Some(2_000));
+    }
+
+    #[test]
+    fn stops_after_max_attempts() {
+        let policy = ReconnectPolicy { max_attempts: Some(3), ..policy() };
+        assert!(policy.delay_for(3, 0.0).is_some());
+        assert_eq!(policy.delay_for(4, 0.0), None);
+        assert_eq!(policy.delay_for(0, 0.0), None);
+
+        let unlimited = ReconnectPolicy { max_attempts: None, ..policy };
+        assert!(unlimited.delay_for(1_000, 0.0).is_some());
+
+        let disabled = ReconnectPolicy { enabled: false, ..ReconnectPolicy::default() };
+        assert_eq!(disabled.delay_for(1, 0.0), None);
+    }
+
+    #[test]
+    fn validate_rejects_bad_options() {
+        assert!(ReconnectPolicy::default().validate().is_ok());
+        assert!(ReconnectPolicy { initial_delay_ms: 0, ..policy() }.validate().is_err());
+        assert!(ReconnectPolicy { max_delay_ms: 100, ..policy() }.validate().is_err());
+        assert!(ReconnectPolicy { multiplier: 0.5, ..policy() }.validate().is_err());
+        assert!(ReconnectPolicy { multiplier: f64::NAN, ..policy() }.validate().is_err());
+        assert!(ReconnectPolicy { jitter: 1.5, ..policy() }.validate().is_err());
+    }
+}
@@ -0,0 +1,181 @@
+use std::collections::HashMap;
+use std::rc::Rc;
+use wasm_bindgen::prelude::*;
+use wasm_bindgen_futures::spawn_local;
+use web_sys::{console, MediaStream};
+use crate::signaling::{Envelope, SignalingMessage};
+use crate::socket::SignalingSender;
+use crate::error::ClientError;
+use crate::webrtc_peer_connection::WebRTCConnection;
+use crate::ClientInner;
+
+// 参加中のルームと、ルーム内の相手ごとの WebRTCConnection (メッシュ接続)
+#[derive(Default)]
+pub(crate) struct Room {
+    pub name: Option<String>,
+    pub peers: HashMap<String, WebRTCConnection>,
+}
+
+impl ClientInner {
+    // 受信したシグナリングメッセージを宛先のピア接続に振り分ける
+    pub(crate) fn route_envelope(self: &Rc<Self>, envelope: Envelope) {
+        let Envelope { from, to, room, message } = envelope;
+        // 自分が送ったメッセージ (echo サーバーなど) と他人宛てのメッセージは無視
+        if from.as_deref() == Some(self.peer_id.as_str()) {
+            return;
+        }
+        if to.as_deref().is_some_and(|to| to != self.peer_id) {
+            return;
+        }
+
+        let Some(room) = room else {
+            // ルーム外のメッセージは従来どおり1対1の接続で処理
+            if let SignalingMessage::Bye = message {
+                self.end_call(from.as_deref());
+                return;
+            }
+            let connection = self.default_connection();
+            if let Some(from) = from.as_deref() {
+                connection.assign_role(from);
+            }
+            self.handle_signaling(connection, message);
+            return;
+        };
+        if self.room.borrow().name.as_deref() != Some(room.as_str()) {
+            return;
+        }
+        let Some(from) = from else {
+            self.report_error(ClientError::Protocol(format!("{} message without sender in room {}", message.kind(), room)));
+            return;
+        };
+
+        match message {
+            SignalingMessage::Join { .. } => {
+                if self.room.borrow().peers.contains_key(&from) {
+                    return;
+                }
+                // 既存のメンバーが新しく参加したピアにオファーを送る
+                match self.room_peer(&from) {
+                    Ok(connection) => {
+                        let weak = Rc::downgrade(self);
+                        spawn_local(async move {
+                            if let Err(e) = connection.negotiate().await {
+                                if let Some(inner) = weak.upgrade() {
+                                    inner.report_error(e);
+                                }
+                            }
+                        });
+                    }
+                    Err(e) => self.report_error(ClientError::negotiation(e)),
+                }
+            }
+            SignalingMessage::Leave { .. } | SignalingMessage::Bye => self.remove_room_peer(&from),
+            message => match self.room_peer(&from) {
+                Ok(connection) => self.handle_signaling(connection, message),
+                Err(e) => self.report_error(ClientError::negotiation(e)),
+            },
+        }
+    }
+
+    // ルーム内のピアとの接続を取得、無ければ作成する
+    pub(crate) fn room_peer(self: &Rc<Self>, peer_id: &str) -> Result<WebRTCConnection, JsValue> {
+        if let Some(connection) = self.room.borrow().peers.get(peer_id) {
+            return Ok(connection.clone());
+        }
+
+        let room_name = self.room.borrow().name.clone();
+        let sender = SignalingSender::routed(self.socket.clone(), &self.peer_id, Some(peer_id), room_name.as_deref());
+        let connection = WebRTCConnection::with_signaling(sender, &self.ice_servers.borrow())?;
+
+        let weak = Rc::downgrade(self);
+        let remote_id = peer_id.to_string();
+        connection.set_track_handler(Rc::new(move |stream: MediaStream| {
+            let Some(inner) = weak.upgrade() else {
+                return;
+            };
+            let callback = inner.callbacks.borrow().on_peer_track.clone();
+            if let Some(callback) = callback {
+                let _ = callback.call2(&JsValue::NULL, &JsValue::from_str(&remote_id), &stream);
+            }
+            inner.emit_remote_track(&stream, Some(&remote_id));
+        }));
+        connection.set_candidate_error_handler(self.candidate_error_handler());
+        connection.set_data_channel_handler(self.data_channel_handler(Some(peer_id)));
+        connection.set_ice_recovery_handler(self.ice_recovery_handler(Some(peer_id)));
+        connection.set_error_handler(self.error_handler());
+        if let Some(stream) = self.local_stream.borrow().as_ref() {
+            connection.add_media_stream(stream)?;
+        }
+        self.apply_screen_share(&connection);
+        if let Err(e) = connection.set_codec_orders(&self.codec_order.borrow()) {
+            self.report_error(e);
+        }
+        connection.store_send_limits(&self.send_limits.borrow());
+        self.announce_mute_state(&connection);
+
+        self.room.borrow_mut().peers.insert(peer_id.to_string(), connection.clone());
+        console::log_1(&format!("Peer joined: {}", peer_id).into());
+        let callback = self.callbacks.borrow().on_peer_joined.clone();
+        if let Some(callback) = callback {
+            let _ = callback.call1(&JsValue::NULL, &JsValue::from_str(peer_id));
+        }
+        Ok(connection)
+    }
+
+    pub(crate) fn remove_room_peer(&self, peer_id: &str) {
+        let Some(connection) = self.room.borrow_mut().peers.remove(peer_id) else {
+            return;
+        };
+        connection.close();
+        console::log_1(&format!("Peer left: {}", peer_id).into());
+        let callback = self.callbacks.borrow().on_peer_left.clone();
+        if let Some(callback) = callback {
+            let _ = callback.call1(&JsValue::NULL, &JsValue::from_str(peer_id));
+        }
+    }
+
+    pub(crate) fn join_room(&self, room: &str) -> Result<(), ClientError> {
+        if room.is_empty() {
+            return Err(ClientError::Protocol("room name must not be empty".to_string()));
+        }
+        if self.room.borrow().name.is_some() {
+            self.leave_room()?;
+        }
+        self.room.borrow_mut().name = Some(room.to_string());
+        self.announce_join().map_err(ClientError::signaling)
+    }
+
+    // 参加中のルームに join を送る (再接続後にも呼ぶ)
+    pub(crate) fn announce_join(&self) -> Result<(), JsValue> {
+        let Some(room) = self.room.borrow().name.clone() else {
+            return Ok(());
+        };
+        let sender = SignalingSender::routed(self.socket.clone(), &self.peer_id, None, Some(&room));
+        sender.send(SignalingMessage::Join { room })
+    }
+
+    // 再接続後に呼ぶ。切断時にサーバーが他のメンバーへ leave を送っているので、
+    // 古い接続は閉じてから参加し直し、メンバー側からのオファーを待つ
+    pub(crate) fn rejoin_room(&self) -> Result<(), JsValue> {
+        self.remove_all_room_peers();
+        self.announce_join()
+    }
+
+    fn remove_all_room_peers(&self) {
+        let peer_ids: Vec<String> = self.room.borrow().peers.keys().cloned().collect();
+        for peer_id in peer_ids {
+            self.remove_room_peer(&peer_id);
+        }
+    }
+
+    pub(crate) fn leave_room(&self) -> Result<(), ClientError> {
+        let Some(room) = self.room.borrow().name.clone() else {
+            return Ok(());
+        };
+        let sender = SignalingSender::routed(self.socket.clone(), &self.peer_id, None, Some(&room));
+        let result = sender.send(SignalingMessage::Leave { room });
+        self.remove_all_room_peers();
+        self.room.borrow_mut().name = None;
+        result.map_err(ClientError::signaling)
+    }
+}
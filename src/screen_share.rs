@@ -0,0 +1,123 @@
+use std::rc::Rc;
+use wasm_bindgen::prelude::*;
+use wasm_bindgen_futures::spawn_local;
+use web_sys::{console, MediaStream, MediaStreamTrack};
+use crate::error::ClientError;
+use crate::media;
+use crate::webrtc_peer_connection::WebRTCConnection;
+use crate::ClientInner;
+
+// 画面共有中の状態
+// 共有中は全ての接続の映像トラックを画面のトラックに差し替え、終了時にカメラのトラックへ戻す
+pub(crate) struct ScreenShare {
+    pub stream: MediaStream,
+    track: MediaStreamTrack,
+    // ブラウザの「共有を停止」ボタンで発火する ended イベント
+    _on_ended: Closure<dyn FnMut()>,
+}
+
+impl ClientInner {
+    pub(crate) async fn start_screen_share(self: &Rc<Self>) -> Result<MediaStream, ClientError> {
+        if self.screen_share.borrow().is_some() {
+            return Err(ClientError::Media("screen share already started".to_string()));
+        }
+        let stream = media::get_display_media().await.map_err(ClientError::media)?;
+        let Some(track) = first_track(&stream) else {
+            return Err(ClientError::Media("no video track was captured".to_string()));
+        };
+
+        // カメラを送っていない接続には画面のトラックを追加する
+        // 途中で失敗したら、切り替え済みの接続をカメラに戻してから返す
+        let connections = self.connections();
+        for (index, connection) in connections.iter().enumerate() {
+            if let Err(e) = connection.send_track(&track, &stream).await {
+                stop_tracks(&stream);
+                self.restore_camera(&track, &connections[..index]).await;
+                return Err(ClientError::media(e));
+            }
+        }
+
+        let weak = Rc::downgrade(self);
+        let on_ended = Closure::wrap(Box::new(move || {
+            let Some(inner) = weak.upgrade() else {
+                return;
+            };
+            spawn_local(async move {
+                if inner.stop_screen_share().await {
+                    console::log_1(&"Screen share ended by the user".into());
+                    let callback = inner.callbacks.borrow().on_screen_share_ended.clone();
+                    if let Some(callback) = callback {
+                        let _ = callback.call0(&JsValue::NULL);
+                    }
+                }
+            });
+        }) as Box<dyn FnMut()>);
+        track.set_onended(Some(on_ended.as_ref().unchecked_ref()));
+
+        self.screen_share.replace(Some(ScreenShare { stream: stream.clone(), track, _on_ended: on_ended }));
+        Ok(stream)
+    }
+
+    // 画面共有を止めてカメラの映像に戻す。共有していなければ false
+    pub(crate) async fn stop_screen_share(&self) -> bool {
+        let Some(screen) = self.screen_share.borrow().as_ref().map(|share| share.track.clone()) else {
+            return false;
+        };
+        self.end_screen_share();
+        self.restore_camera(&screen, &self.connections()).await;
+        true
+    }
+
+    // 画面のトラックを送っている接続をカメラの映像に戻す
+    async fn restore_camera(&self, screen: &MediaStreamTrack, connections: &[WebRTCConnection]) {
+        let camera = self.local_stream.borrow().as_ref().and_then(first_track);
+        // カメラが無ければ追加した画面の送信を取り除く
+        let Some(camera) = camera else {
+            for connection in connections {
+                connection.remove_track(screen);
+            }
+            return;
+        };
+        for connection in connections {
+            if let Err(e) = connection.replace_track(camera.clone()).await {
+                self.report_error(ClientError::media(e));
+            }
+        }
+    }
+
+    // 画面のキャプチャだけを止める (接続のトラックは戻さない)。共有していなければ false
+    pub(crate) fn end_screen_share(&self) -> bool {
+        let Some(share) = self.screen_share.take() else {
+            return false;
+        };
+        share.track.set_onended(None);
+        stop_tracks(&share.stream);
+        true
+    }
+
+    // 画面共有中に作られた接続にも画面のトラックを送る
+    pub(crate) fn apply_screen_share(self: &Rc<Self>, connection: &WebRTCConnection) {
+        let Some((track, stream)) = self.screen_share.borrow().as_ref().map(|share| (share.track.clone(), share.stream.clone())) else {
+            return;
+        };
+        let connection = connection.clone();
+        let weak = Rc::downgrade(self);
+        spawn_local(async move {
+            if let Err(e) = connection.send_track(&track, &stream).await {
+                if let Some(inner) = weak.upgrade() {
+                    inner.report_error(ClientError::media(e));
+                }
+            }
+        });
+    }
+}
+
+fn first_track(stream: &MediaStream) -> Option<MediaStreamTrack> {
+    stream.get_video_tracks().iter().find_map(|track| track.dyn_into::<MediaStreamTrack>().ok())
+}
+
+fn stop_tracks(stream: &MediaStream) {
+    for track in stream.get_tracks().iter().filter_map(|track| track.dyn_into::<MediaStreamTrack>().ok()) {
+        track.stop();
+    }
+}
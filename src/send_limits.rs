@@ -0,0 +1,191 @@
+use js_sys::{Function, Reflect};
+use serde::{Deserialize, Serialize};
+use wasm_bindgen::prelude::*;
+use wasm_bindgen_futures::JsFuture;
+use web_sys::RtcRtpSender;
+use crate::signaling::TrackKind;
+
+// 解像度とフレームレートのどちらを優先して落とすか (RTCDegradationPreference)
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum DegradationPreference {
+    MaintainFramerate,
+    MaintainResolution,
+    Balanced,
+}
+
+impl DegradationPreference {
+    fn as_str(self) -> &'static str {
+        match self {
+            DegradationPreference::MaintainFramerate => "maintain-framerate",
+            DegradationPreference::MaintainResolution => "maintain-resolution",
+            DegradationPreference::Balanced => "balanced",
+        }
+    }
+}
+
+// 送信の上限。指定しなかった項目はブラウザの既定に戻す
+// JS からは { maxBitrate, maxFramerate, scaleResolutionDownBy, degradationPreference }
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase", deny_unknown_fields)]
+pub struct SendLimits {
+    // bps
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub max_bitrate: Option<u32>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub max_framerate: Option<f64>,
+    // 2.0 なら縦横それぞれ 1/2
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub scale_resolution_down_by: Option<f64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub degradation_preference: Option<DegradationPreference>,
+}
+
+impl SendLimits {
+    pub fn is_empty(&self) -> bool {
+        *self == SendLimits::default()
+    }
+
+    // 範囲外の値や、音声に映像用の項目を指定した場合はエラー
+    pub fn validate(&self, kind: TrackKind) -> Result<(), String> {
+        if self.max_bitrate == Some(0) {
+            return Err("maxBitrate must be greater than 0".to_string());
+        }
+        if self.max_framerate.is_some_and(|framerate| !framerate.is_finite() || framerate <= 0.0) {
+            return Err("maxFramerate must be greater than 0".to_string());
+        }
+        if self.scale_resolution_down_by.is_some_and(|scale| !scale.is_finite() || scale < 1.0) {
+            return Err("scaleResolutionDownBy must be 1.0 or greater".to_string());
+        }
+        let video_only = self.max_framerate.is_some() || self.scale_resolution_down_by.is_some() || self.degradation_preference.is_some();
+        if kind == TrackKind::Audio && video_only {
+            return Err("maxFramerate, scaleResolutionDownBy and degradationPreference only apply to video".to_string());
+        }
+        Ok(())
+    }
+}
+
+// 種類ごとの上限 (None なら制限なし)
+#[derive(Debug, Clone, Default)]
+pub(crate) struct SendLimitsByKind {
+    audio: Option<SendLimits>,
+    video: Option<SendLimits>,
+}
+
+impl SendLimitsByKind {
+    pub fn get(&self, kind: TrackKind) -> Option<&SendLimits> {
+        match kind {
+            TrackKind::Audio => self.audio.as_ref(),
+            TrackKind::Video => self.video.as_ref(),
+        }
+    }
+
+    pub fn set(&mut self, kind: TrackKind, limits: SendLimits) {
+        let limits = if limits.is_empty() { None } else { Some(limits) };
+        match kind {
+            TrackKind::Audio => self.audio = limits,
+            TrackKind::Video => self.video = limits,
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.audio.is_none() && self.video.is_none()
+    }
+}
+
+// getParameters で取った値を書き換えて setParameters に渡す (再ネゴシエーションは不要)
+// ネゴシエーション前で encodings が空の場合は何もせず false を返す
+// 同時送信 (simulcast) の場合は全ての encoding に同じ上限を設定する
+pub(crate) async fn apply(sender: &RtcRtpSender, limits: &SendLimits) -> Result<bool, JsValue> {
+    let get = Reflect::get(sender, &"getParameters".into())?.dyn_into::<Function>()?;
+    let parameters = get.call0(sender)?;
+    let encodings = Reflect::get(&parameters, &"encodings".into())?;
+    let Ok(encodings) = encodings.dyn_into::<js_sys::Array>() else {
+        return Ok(false);
+    };
+    if encodings.length() == 0 {
+        return Ok(false);
+    }
+    for encoding in encodings.iter() {
+        set_or_delete(&encoding, "maxBitrate", limits.max_bitrate.map(JsValue::from))?;
+        set_or_delete(&encoding, "maxFramerate", limits.max_framerate.map(JsValue::from))?;
+        set_or_delete(&encoding, "scaleResolutionDownBy", limits.scale_resolution_down_by.map(JsValue::from))?;
+    }
+    let preference = limits.degradation_preference.map(|preference| JsValue::from_str(preference.as_str()));
+    set_or_delete(&parameters, "degradationPreference", preference)?;
+
+    // 不正な値のときは例外になるので call で呼ぶ
+    let set = Reflect::get(sender, &"setParameters".into())?.dyn_into::<Function>()?;
+    let promise = set.call1(sender, &parameters)?.dyn_into::<js_sys::Promise>()?;
+    JsFuture::from(promise).await?;
+    Ok(true)
+}
+
+fn set_or_delete(target: &JsValue, key: &str, value: Option<JsValue>) -> Result<(), JsValue> {
+    match value {
+        Some(value) => Reflect::set(target, &key.into(), &value)?,
+        None => Reflect::delete_property(target.unchecked_ref::<js_sys::Object>(), &key.into())?,
+    };
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn accepts_valid_limits() {
+        let video = SendLimits {
+            max_bitrate: Some(300_000),
+            max_framerate: Some(15.0),
+            scale_resolution_down_by: Some(1.0),
+            degradation_preference: Some(DegradationPreference::Balanced),
+        };
+        assert!(video.validate(TrackKind::Video).is_ok());
+        let audio = SendLimits { max_bitrate: Some(32_000), ..SendLimits::default() };
+        assert!(audio.validate(TrackKind::Audio).is_ok());
+        assert!(SendLimits::default().validate(TrackKind::Audio).is_ok());
+    }
+
+    #[test]
+    fn rejects_zero_bitrate() {
+        let limits = SendLimits { max_bitrate: Some(0), ..SendLimits::default() };
+        assert!(limits.validate(TrackKind::Video).is_err());
+        assert!(limits.validate(TrackKind::Audio).is_err());
+    }
+
+    #[test]
+    fn rejects_bad_framerate_and_scale() {
+        for framerate in [0.0, -1.0, f64::NAN, f64::INFINITY] {
+            let limits = SendLimits { max_framerate: Some(framerate), ..SendLimits::default() };
+            assert!(limits.validate(TrackKind::Video).is_err(), "framerate {}", framerate);
+        }
+        for scale in [0.5, 0.999, f64::NAN] {
+            let limits = SendLimits { scale_resolution_down_by: Some(scale), ..SendLimits::default() };
+            assert!(limits.validate(TrackKind::Video).is_err(), "scale {}", scale);
+        }
+    }
+
+    #[test]
+    fn rejects_video_fields_for_audio() {
+        let cases = [
+            SendLimits { max_framerate: Some(15.0), ..SendLimits::default() },
+            SendLimits { scale_resolution_down_by: Some(2.0), ..SendLimits::default() },
+            SendLimits { degradation_preference: Some(DegradationPreference::MaintainFramerate), ..SendLimits::default() },
+        ];
+        for limits in cases {
+            assert!(limits.validate(TrackKind::Audio).is_err());
+            assert!(limits.validate(TrackKind::Video).is_ok());
+        }
+    }
+
+    #[test]
+    fn empty_limits_clear_the_kind() {
+        let mut by_kind = SendLimitsByKind::default();
+        by_kind.set(TrackKind::Video, SendLimits { max_bitrate: Some(1), ..SendLimits::default() });
+        assert!(by_kind.get(TrackKind::Video).is_some());
+        assert!(by_kind.get(TrackKind::Audio).is_none());
+        by_kind.set(TrackKind::Video, SendLimits::default());
+        assert!(by_kind.is_empty());
+    }
+}
@@ -0,0 +1,360 @@
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::fmt;
+
+// シグナリングメッセージの型定義
+// WebSocket上でやり取りするJSONは全て "type" フィールドで種類を判別する。
+// offer / answer / icecandidate / bye / join / leave / mute 以外の type はアプリ定義メッセージとして扱う。
+#[derive(Debug, Clone, PartialEq)]
+pub enum SignalingMessage {
+    Offer { sdp: String },
+    Answer { sdp: String },
+    IceCandidate { candidate: IceCandidate },
+    Bye,
+    // ルームへの参加・退出
+    Join { room: String },
+    Leave { room: String },
+    // 送信側でマイク・カメラをオフ (muted: true) / オンにした
+    Mute { kind: TrackKind, muted: bool },
+    App { kind: String, payload: Value },
+}
+
+// トラックの種類 (MediaStreamTrack.kind と同じ文字列)
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum TrackKind {
+    Audio,
+    Video,
+}
+
+impl TrackKind {
+    pub fn parse(kind: &str) -> Option<TrackKind> {
+        match kind {
+            "audio" => Some(TrackKind::Audio),
+            "video" => Some(TrackKind::Video),
+            _ => None,
+        }
+    }
+
+    pub fn as_str(self) -> &'static str {
+        match self {
+            TrackKind::Audio => "audio",
+            TrackKind::Video => "video",
+        }
+    }
+}
+
+// RTCIceCandidate.toJSON() と同じ形のICE Candidate
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct IceCandidate {
+    pub candidate: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub sdp_mid: Option<String>,
+    #[serde(rename = "sdpMLineIndex", default, skip_serializing_if = "Option::is_none")]
+    pub sdp_m_line_index: Option<u16>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub username_fragment: Option<String>,
+}
+
+#[derive(Debug)]
+pub enum SignalingError {
+    // JSONとして解釈できない (チャットの平文など)
+    NotJson(serde_json::Error),
+    // JSONだが "type" が無い、または文字列ではない
+    MissingType,
+    // 既知の type だが必須フィールドが欠けている・型が違う
+    Malformed { kind: String, source: serde_json::Error },
+    Encode(serde_json::Error),
+}
+
+impl fmt::Display for SignalingError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            SignalingError::NotJson(e) => write!(f, "signaling message is not JSON: {}", e),
+            SignalingError::MissingType => write!(f, "signaling message has no string \"type\" field"),
+            SignalingError::Malformed { kind, source } => {
+                write!(f, "malformed \"{}\" signaling message: {}", kind, source)
+            }
+            SignalingError::Encode(e) => write!(f, "failed to encode signaling message: {}", e),
+        }
+    }
+}
+
+impl std::error::Error for SignalingError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            SignalingError::NotJson(e) | SignalingError::Encode(e) => Some(e),
+            SignalingError::Malformed { source, .. } => Some(source),
+            SignalingError::MissingType => None,
+        }
+    }
+}
+
+// 既知メッセージのワイヤーフォーマット
+#[derive(Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "lowercase")]
+enum WireMessage {
+    Offer { sdp: String },
+    Answer { sdp: String },
+    IceCandidate { candidate: IceCandidate },
+    Bye,
+    Join { room: String },
+    Leave { room: String },
+    Mute { kind: TrackKind, muted: bool },
+}
+
+impl SignalingMessage {
+    fn from_value(value: Value) -> Result<SignalingMessage, SignalingError> {
+        let kind = value
+            .get("type")
+            .and_then(Value::as_str)
+            .ok_or(SignalingError::MissingType)?
+            .to_string();
+
+        if !is_reserved_kind(&kind) {
+            return Ok(SignalingMessage::App { kind, payload: value });
+        }
+
+        let wire: WireMessage = serde_json::from_value(value)
+            .map_err(|source| SignalingError::Malformed { kind, source })?;
+        Ok(match wire {
+            WireMessage::Offer { sdp } => SignalingMessage::Offer { sdp },
+            WireMessage::Answer { sdp } => SignalingMessage::Answer { sdp },
+            WireMessage::IceCandidate { candidate } => SignalingMessage::IceCandidate { candidate },
+            WireMessage::Bye => SignalingMessage::Bye,
+            WireMessage::Join { room } => SignalingMessage::Join { room },
+            WireMessage::Leave { room } => SignalingMessage::Leave { room },
+            WireMessage::Mute { kind, muted } => SignalingMessage::Mute { kind, muted },
+        })
+    }
+
+    fn to_value(&self) -> Result<Value, SignalingError> {
+        let wire = match self {
+            SignalingMessage::Offer { sdp } => WireMessage::Offer { sdp: sdp.clone() },
+            SignalingMessage::Answer { sdp } => WireMessage::Answer { sdp: sdp.clone() },
+            SignalingMessage::IceCandidate { candidate } => WireMessage::IceCandidate {
+                candidate: candidate.clone(),
+            },
+            SignalingMessage::Bye => WireMessage::Bye,
+            SignalingMessage::Join { room } => WireMessage::Join { room: room.clone() },
+            SignalingMessage::Leave { room } => WireMessage::Leave { room: room.clone() },
+            SignalingMessage::Mute { kind, muted } => WireMessage::Mute { kind: *kind, muted: *muted },
+            SignalingMessage::App { kind, payload } => {
+                // オブジェクトならそのまま type を上書き、それ以外は payload フィールドに包む
+                let mut object = match payload {
+                    Value::Object(map) => map.clone(),
+                    other => {
+                        let mut map = serde_json::Map::new();
+                        map.insert("payload".to_string(), other.clone());
+                        map
+                    }
+                };
+                object.insert("type".to_string(), Value::String(kind.clone()));
+                return Ok(Value::Object(object));
+            }
+        };
+        serde_json::to_value(&wire).map_err(SignalingError::Encode)
+    }
+
+    pub fn kind(&self) -> &str {
+        match self {
+            SignalingMessage::Offer { .. } => "offer",
+            SignalingMessage::Answer { .. } => "answer",
+            SignalingMessage::IceCandidate { .. } => "icecandidate",
+            SignalingMessage::Bye => "bye",
+            SignalingMessage::Join { .. } => "join",
+            SignalingMessage::Leave { .. } => "leave",
+            SignalingMessage::Mute { .. } => "mute",
+            SignalingMessage::App { kind, .. } => kind,
+        }
+    }
+}
+
+// 送信元・宛先・ルームを付けたシグナリングメッセージ
+// { "type": ..., "from": "peer-a", "to": "peer-b", "room": "room-1", ... } の形で送る
+// to が無いメッセージはルーム内の全員宛て
+#[derive(Debug, Clone, PartialEq)]
+pub struct Envelope {
+    pub from: Option<String>,
+    pub to: Option<String>,
+    pub room: Option<String>,
+    pub message: SignalingMessage,
+}
+
+impl Envelope {
+    pub fn decode(text: &str) -> Result<Envelope, SignalingError> {
+        let value: Value = serde_json::from_str(text).map_err(SignalingError::NotJson)?;
+        let field = |name: &str| value.get(name).and_then(Value::as_str).map(str::to_string);
+        let (from, to, room) = (field("from"), field("to"), field("room"));
+        let message = SignalingMessage::from_value(value)?;
+        Ok(Envelope { from, to, room, message })
+    }
+
+    pub fn encode(&self) -> Result<String, SignalingError> {
+        let mut value = self.message.to_value()?;
+        if let Value::Object(object) = &mut value {
+            for (name, field) in [("from", &self.from), ("to", &self.to), ("room", &self.room)] {
+                if let Some(field) = field {
+                    object.insert(name.to_string(), Value::String(field.clone()));
+                }
+            }
+        }
+        serde_json::to_string(&value).map_err(SignalingError::Encode)
+    }
+}
+
+fn is_reserved_kind(kind: &str) -> bool {
+    matches!(kind, "offer" | "answer" | "icecandidate" | "bye" | "join" | "leave" | "mute")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn envelope(message: SignalingMessage) -> Envelope {
+        Envelope { from: None, to: None, room: None, message }
+    }
+
+    fn round_trip(envelope: &Envelope) -> Envelope {
+        Envelope::decode(&envelope.encode().unwrap()).unwrap()
+    }
+
+    #[test]
+    fn known_messages_round_trip() {
+        let messages = vec![
+            SignalingMessage::Offer { sdp: "v=0\r\no=- 1 2 IN IP4 127.0.0.1\r\n".to_string() },
+            SignalingMessage::Answer { sdp: "v=0\r\n".to_string() },
+            SignalingMessage::IceCandidate {
+                candidate: IceCandidate {
+                    candidate: "candidate:1 1 udp 2122260223 192.168.0.2 54321 typ host".to_string(),
+                    sdp_mid: Some("0".to_string()),
+                    sdp_m_line_index: Some(0),
+                    username_fragment: Some("abcd".to_string()),
+                },
+            },
+            SignalingMessage::IceCandidate {
+                candidate: IceCandidate {
+                    candidate: String::new(),
+                    sdp_mid: None,
+                    sdp_m_line_index: None,
+                    username_fragment: None,
+                },
+            },
+            SignalingMessage::Bye,
+            SignalingMessage::Join { room: "room-1".to_string() },
+            SignalingMessage::Leave { room: "room-1".to_string() },
+            SignalingMessage::Mute { kind: TrackKind::Audio, muted: true },
+            SignalingMessage::Mute { kind: TrackKind::Video, muted: false },
+        ];
+        for message in messages {
+            // join / leave の room はエンベロープの room としても読まれるのでメッセージだけ比べる
+            assert_eq!(round_trip(&envelope(message.clone())).message, message);
+        }
+    }
+
+    #[test]
+    fn wire_format_uses_type_tag() {
+        let text = envelope(SignalingMessage::Mute { kind: TrackKind::Video, muted: true }).encode().unwrap();
+        let value: Value = serde_json::from_str(&text).unwrap();
+        assert_eq!(value, json!({ "type": "mute", "kind": "video", "muted": true }));
+
+        let text = envelope(SignalingMessage::Bye).encode().unwrap();
+        assert_eq!(text, r#"{"type":"bye"}"#);
+    }
+
+    #[test]
+    fn decodes_browser_candidate_json() {
+        let text = r#"{"type":"icecandidate","candidate":{"candidate":"candidate:1","sdpMid":"0","sdpMLineIndex":1,"usernameFragment":null}}"#;
+        let decoded = Envelope::decode(text).unwrap();
+        match decoded.message {
+            SignalingMessage::IceCandidate { candidate } => {
+                assert_eq!(candidate.sdp_mid.as_deref(), Some("0"));
+                assert_eq!(candidate.sdp_m_line_index, Some(1));
+                assert_eq!(candidate.username_fragment, None);
+            }
+            other => panic!("unexpected message: {:?}", other),
+        }
+    }
+
+    #[test]
+    fn plain_text_is_not_json() {
+        assert!(matches!(Envelope::decode("hello"), Err(SignalingError::NotJson(_))));
+    }
+
+    #[test]
+    fn missing_or_non_string_type() {
+        assert!(matches!(Envelope::decode(r#"{"sdp":"v=0"}"#), Err(SignalingError::MissingType)));
+        assert!(matches!(Envelope::decode(r#"{"type":1}"#), Err(SignalingError::MissingType)));
+        assert!(matches!(Envelope::decode("[1,2]"), Err(SignalingError::MissingType)));
+    }
+
+    #[test]
+    fn malformed_known_messages() {
+        match Envelope::decode(r#"{"type":"offer"}"#) {
+            Err(SignalingError::Malformed { kind, .. }) => assert_eq!(kind, "offer"),
+            other => panic!("unexpected result: {:?}", other),
+        }
+        let text = r#"{"type":"icecandidate","candidate":{"candidate":"candidate:1","sdpMLineIndex":"0"}}"#;
+        match Envelope::decode(text) {
+            Err(SignalingError::Malformed { kind, .. }) => assert_eq!(kind, "icecandidate"),
+            other => panic!("unexpected result: {:?}", other),
+        }
+        let text = r#"{"type":"mute","kind":"screen","muted":true}"#;
+        assert!(matches!(Envelope::decode(text), Err(SignalingError::Malformed { .. })));
+    }
+
+    #[test]
+    fn app_message_with_object_payload() {
+        let original = envelope(SignalingMessage::App { kind: "chat".to_string(), payload: json!({ "text": "hi" }) });
+        let text = original.encode().unwrap();
+        let value: Value = serde_json::from_str(&text).unwrap();
+        assert_eq!(value, json!({ "type": "chat", "text": "hi" }));
+
+        let decoded = round_trip(&original);
+        assert_eq!(decoded.message.kind(), "chat");
+        match decoded.message {
+            SignalingMessage::App { payload, .. } => assert_eq!(payload["text"], "hi"),
+            other => panic!("unexpected message: {:?}", other),
+        }
+    }
+
+    #[test]
+    fn app_message_with_non_object_payload() {
+        for payload in [json!(42), json!("text"), json!([1, 2]), Value::Null] {
+            let original = envelope(SignalingMessage::App { kind: "custom".to_string(), payload: payload.clone() });
+            let value: Value = serde_json::from_str(&original.encode().unwrap()).unwrap();
+            assert_eq!(value, json!({ "type": "custom", "payload": payload }));
+
+            match round_trip(&original).message {
+                SignalingMessage::App { kind, payload: decoded } => {
+                    assert_eq!(kind, "custom");
+                    assert_eq!(decoded["payload"], payload);
+                }
+                other => panic!("unexpected message: {:?}", other),
+            }
+        }
+    }
+
+    #[test]
+    fn routing_fields_pass_through() {
+        let original = Envelope {
+            from: Some("peer-a".to_string()),
+            to: Some("peer-b".to_string()),
+            room: Some("room-1".to_string()),
+            message: SignalingMessage::Answer { sdp: "v=0\r\n".to_string() },
+        };
+        assert_eq!(round_trip(&original), original);
+
+        let broadcast = Envelope { to: None, ..original.clone() };
+        let value: Value = serde_json::from_str(&broadcast.encode().unwrap()).unwrap();
+        assert!(value.get("to").is_none());
+        assert_eq!(round_trip(&broadcast), broadcast);
+
+        let decoded = Envelope::decode(r#"{"type":"bye","from":"peer-a","room":7}"#).unwrap();
+        assert_eq!(decoded.from.as_deref(), Some("peer-a"));
+        assert_eq!(decoded.room, None);
+        assert_eq!(decoded.message, SignalingMessage::Bye);
+    }
+}
@@ -0,0 +1,178 @@
+use std::cell::RefCell;
+use std::rc::Rc;
+use wasm_bindgen::JsValue;
+use web_sys::WebSocket;
+use crate::signaling::{Envelope, SignalingMessage};
+use crate::outbound_queue::{OutboundMessage, OutboundQueue, OverflowPolicy, PushOutcome, QueueOptions};
+
+// 再接続で差し替わるWebSocketを共有するためのハンドル
+// WebSocketClient と WebRTCConnection は同じハンドルを持ち、常に最新のソケットへ送信する
+// ソケットが OPEN でない間の送信はキューに溜め、OPEN になったら順番に送る
+#[derive(Clone)]
+pub struct SharedSocket {
+    state: Rc<SocketState>,
+}
+
+struct SocketState {
+    current: RefCell<WebSocket>,
+    queue: RefCell<OutboundQueue>,
+    on_overflow: RefCell<Option<js_sys::Function>>,
+}
+
+impl SharedSocket {
+    pub fn new(ws: WebSocket) -> SharedSocket {
+        SharedSocket::with_queue(ws, QueueOptions::default())
+    }
+
+    pub fn with_queue(ws: WebSocket, options: QueueOptions) -> SharedSocket {
+        SharedSocket {
+            state: Rc::new(SocketState {
+                current: RefCell::new(ws),
+                queue: RefCell::new(OutboundQueue::new(options)),
+                on_overflow: RefCell::new(None),
+            }),
+        }
+    }
+
+    pub fn get(&self) -> WebSocket {
+        self.state.current.borrow().clone()
+    }
+
+    // 新しいソケットに差し替え、古いソケットを返す
+    pub fn replace(&self, ws: WebSocket) -> WebSocket {
+        self.state.current.replace(ws)
+    }
+
+    pub fn set_on_overflow(&self, callback: Option<js_sys::Function>) {
+        self.state.on_overflow.replace(callback);
+    }
+
+    pub fn queue_depth(&self) -> usize {
+        self.state.queue.borrow().len()
+    }
+
+    pub fn send_with_str(&self, text: &str) -> Result<(), JsValue> {
+        self.send(OutboundMessage::Text(text.to_string()))
+    }
+
+    pub fn send_with_u8_array(&self, data: &[u8]) -> Result<(), JsValue> {
+        self.send(OutboundMessage::Binary(data.to_vec()))
+    }
+
+    pub fn send(&self, message: OutboundMessage) -> Result<(), JsValue> {
+        // 順番を守るため、キューが空で OPEN のときだけ直接送る
+        if self.is_open() && self.state.queue.borrow().is_empty() {
+            return send_now(&self.get(), &message);
+        }
+        self.enqueue(message)?;
+        self.flush()
+    }
+
+    // キューに溜まったメッセージを送信する (ソケットが OPEN になったときに呼ぶ)
+    pub fn flush(&self) -> Result<(), JsValue> {
+        if !self.is_open() {
+            return Ok(());
+        }
+        let ws = self.get();
+        loop {
+            let Some(message) = self.state.queue.borrow_mut().pop_front() else {
+                return Ok(());
+            };
+            if let Err(e) = send_now(&ws, &message) {
+                self.state.queue.borrow_mut().push_front(message);
+                return Err(e);
+            }
+        }
+    }
+
+    pub fn is_open(&self) -> bool {
+        self.state.current.borrow().ready_state() == WebSocket::OPEN
+    }
+
+    // CLOSING または CLOSED
+    pub fn is_closed(&self) -> bool {
+        matches!(self.state.current.borrow().ready_state(), WebSocket::CLOSING | WebSocket::CLOSED)
+    }
+
+    fn enqueue(&self, message: OutboundMessage) -> Result<(), JsValue> {
+        let (outcome, policy) = {
+            let mut queue = self.state.queue.borrow_mut();
+            (queue.push(message), queue.policy())
+        };
+        match outcome {
+            PushOutcome::Queued => Ok(()),
+            PushOutcome::DroppedOldest(dropped) => {
+                web_sys::console::warn_1(&format!("Outbound queue full, dropped oldest message: {:?}", dropped).into());
+                Ok(())
+            }
+            PushOutcome::Overflowed(message) => match policy {
+                OverflowPolicy::Callback => {
+                    let callback = self.state.on_overflow.borrow().clone();
+                    if let Some(callback) = callback {
+                        let _ = callback.call1(&JsValue::NULL, &to_js(&message));
+                    }
+                    Ok(())
+                }
+                _ => Err(JsValue::from_str("outbound queue is full")),
+            },
+        }
+    }
+}
+
+fn send_now(ws: &WebSocket, message: &OutboundMessage) -> Result<(), JsValue> {
+    match message {
+        OutboundMessage::Text(text) => ws.send_with_str(text),
+        OutboundMessage::Binary(data) => ws.send_with_u8_array(data),
+    }
+}
+
+fn to_js(message: &OutboundMessage) -> JsValue {
+    match message {
+        OutboundMessage::Text(text) => JsValue::from_str(text),
+        OutboundMessage::Binary(data) => js_sys::Uint8Array::from(data.as_slice()).into(),
+    }
+}
+
+// シグナリングメッセージの送信口
+// ルーム内の特定ピアとやり取りする WebRTCConnection は from / to / room を付けて送信する
+#[derive(Clone)]
+pub struct SignalingSender {
+    socket: SharedSocket,
+    from: Option<String>,
+    to: Option<String>,
+    room: Option<String>,
+}
+
+impl SignalingSender {
+    pub fn new(socket: SharedSocket) -> SignalingSender {
+        SignalingSender { socket, from: None, to: None, room: None }
+    }
+
+    pub fn routed(socket: SharedSocket, from: &str, to: Option<&str>, room: Option<&str>) -> SignalingSender {
+        SignalingSender {
+            socket,
+            from: Some(from.to_string()),
+            to: to.map(str::to_string),
+            room: room.map(str::to_string),
+        }
+    }
+
+    pub fn local_peer_id(&self) -> Option<&str> {
+        self.from.as_deref()
+    }
+
+    pub fn remote_peer_id(&self) -> Option<&str> {
+        self.to.as_deref()
+    }
+
+    pub fn send(&self, message: SignalingMessage) -> Result<(), JsValue> {
+        let envelope = Envelope {
+            from: self.from.clone(),
+            to: self.to.clone(),
+            room: self.room.clone(),
+            message,
+        };
+        let text = envelope.encode().map_err(|e| JsValue::from_str(&e.to_string()))?;
+        self.socket.send_with_str(&text)
+    }
+}
@@ -0,0 +1,376 @@
+use std::collections::HashMap;
+use js_sys::Reflect;
+use serde::{Deserialize, Serialize};
+use wasm_bindgen::prelude::*;
+use wasm_bindgen_futures::JsFuture;
+use web_sys::RtcPeerConnection;
+
+// RTCPeerConnection.getStats() の1件分 (必要なフィールドだけ)
+// https://www.w3.org/TR/webrtc-stats/
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default, rename_all = "camelCase")]
+struct RawStats {
+    id: String,
+    #[serde(rename = "type")]
+    kind_of_stats: String,
+    timestamp: f64,
+    kind: Option<String>,
+    bytes_received: Option<f64>,
+    bytes_sent: Option<f64>,
+    packets_received: Option<f64>,
+    packets_sent: Option<f64>,
+    packets_lost: Option<f64>,
+    jitter: Option<f64>,
+    frames_per_second: Option<f64>,
+    frame_width: Option<u32>,
+    frame_height: Option<u32>,
+    round_trip_time: Option<f64>,
+    current_round_trip_time: Option<f64>,
+    available_outgoing_bitrate: Option<f64>,
+    state: Option<String>,
+    nominated: Option<bool>,
+    // Firefox は candidate-pair に selected を付ける
+    selected: Option<bool>,
+    selected_candidate_pair_id: Option<String>,
+    local_candidate_id: Option<String>,
+    remote_candidate_id: Option<String>,
+    address: Option<String>,
+    port: Option<u16>,
+    protocol: Option<String>,
+    candidate_type: Option<String>,
+}
+
+// 受信ストリームの統計。bitrate / packetsLostDelta は前回の取得からの差分
+#[derive(Debug, Clone, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct InboundStats {
+    pub id: String,
+    pub kind: String,
+    pub bytes_received: f64,
+    pub packets_received: f64,
+    pub packets_lost: f64,
+    // 秒
+    pub jitter: Option<f64>,
+    pub frames_per_second: Option<f64>,
+    pub frame_width: Option<u32>,
+    pub frame_height: Option<u32>,
+    // bps
+    pub bitrate: Option<f64>,
+    pub packets_lost_delta: Option<f64>,
+}
+
+#[derive(Debug, Clone, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct OutboundStats {
+    pub id: String,
+    pub kind: String,
+    pub bytes_sent: f64,
+    pub packets_sent: f64,
+    pub frames_per_second: Option<f64>,
+    pub frame_width: Option<u32>,
+    pub frame_height: Option<u32>,
+    pub bitrate: Option<f64>,
+    // 相手側 (remote-inbound-rtp) から見た損失と RTT
+    pub remote_packets_lost: Option<f64>,
+    pub round_trip_time: Option<f64>,
+}
+
+// 使用中の ICE candidate ペア
+#[derive(Debug, Clone, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct CandidatePairStats {
+    pub local_address: Option<String>,
+    pub local_port: Option<u16>,
+    // host / srflx / prflx / relay
+    pub local_type: Option<String>,
+    pub remote_address: Option<String>,
+    pub remote_port: Option<u16>,
+    pub remote_type: Option<String>,
+    pub protocol: Option<String>,
+    pub current_round_trip_time: Option<f64>,
+    pub available_outgoing_bitrate: Option<f64>,
+}
+
+#[derive(Debug, Clone, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct StatsSnapshot {
+    // ミリ秒
+    pub timestamp: f64,
+    // 前回の取得からの経過時間 (初回は null)
+    pub interval_ms: Option<f64>,
+    pub inbound: Vec<InboundStats>,
+    pub outbound: Vec<OutboundStats>,
+    pub candidate_pair: Option<CandidatePairStats>,
+    // 秒。candidate ペアの RTT、無ければ remote-inbound-rtp の RTT
+    pub round_trip_time: Option<f64>,
+    // 全ストリームの合計 (bps)
+    pub inbound_bitrate: Option<f64>,
+    pub outbound_bitrate: Option<f64>,
+}
+
+impl StatsSnapshot {
+    fn from_raw(entries: Vec<RawStats>, previous: Option<&StatsSnapshot>) -> StatsSnapshot {
+        let by_id: HashMap<&str, &RawStats> = entries.iter().map(|entry| (entry.id.as_str(), entry)).collect();
+        let timestamp = entries.iter().map(|entry| entry.timestamp).fold(0.0, f64::max);
+        let interval_ms = previous.map(|previous| timestamp - previous.timestamp).filter(|ms| *ms > 0.0);
+        let bitrate = |bytes: f64, previous_bytes: Option<f64>| {
+            let seconds = interval_ms? / 1000.0;
+            Some(((bytes - previous_bytes?) * 8.0 / seconds).max(0.0))
+        };
+
+        let inbound: Vec<InboundStats> = entries
+            .iter()
+            .filter(|entry| entry.kind_of_stats == "inbound-rtp")
+            .map(|entry| {
+                let last = previous.and_then(|previous| previous.inbound.iter().find(|last| last.id == entry.id));
+                let bytes_received = entry.bytes_received.unwrap_or(0.0);
+                let packets_lost = entry.packets_lost.unwrap_or(0.0);
+                InboundStats {
+                    id: entry.id.clone(),
+                    kind: entry.kind.clone().unwrap_or_default(),
+                    bytes_received,
+                    packets_received: entry.packets_received.unwrap_or(0.0),
+                    packets_lost,
+                    jitter: entry.jitter,
+                    frames_per_second: entry.frames_per_second,
+                    frame_width: entry.frame_width,
+                    frame_height: entry.frame_height,
+                    bitrate: bitrate(bytes_received, last.map(|last| last.bytes_received)),
+                    // カウンタがリセットされた場合は bitrate と同じく 0 にする
+                    packets_lost_delta: last.map(|last| (packets_lost - last.packets_lost).max(0.0)),
+                }
+            })
+            .collect();
+
+        let remote_inbound: Vec<&RawStats> = entries
+            .iter()
+            .filter(|entry| entry.kind_of_stats == "remote-inbound-rtp")
+            .collect();
+        let outbound: Vec<OutboundStats> = entries
+            .iter()
+            .filter(|entry| entry.kind_of_stats == "outbound-rtp")
+            .map(|entry| {
+                let last = previous.and_then(|previous| previous.outbound.iter().find(|last| last.id == entry.id));
+                let remote = remote_inbound.iter().find(|remote| remote.kind == entry.kind);
+                let bytes_sent = entry.bytes_sent.unwrap_or(0.0);
+                OutboundStats {
+                    id: entry.id.clone(),
+                    kind: entry.kind.clone().unwrap_or_default(),
+                    bytes_sent,
+                    packets_sent: entry.packets_sent.unwrap_or(0.0),
+                    frames_per_second: entry.frames_per_second,
+                    frame_width: entry.frame_width,
+                    frame_height: entry.frame_height,
+                    bitrate: bitrate(bytes_sent, last.map(|last| last.bytes_sent)),
+                    remote_packets_lost: remote.and_then(|remote| remote.packets_lost),
+                    round_trip_time: remote.and_then(|remote| remote.round_trip_time),
+                }
+            })
+            .collect();
+
+        let pair = selected_pair(&entries, &by_id);
+        let candidate_pair = pair.map(|pair| {
+            let local = pair.local_candidate_id.as_deref().and_then(|id| by_id.get(id));
+            let remote = pair.remote_candidate_id.as_deref().and_then(|id| by_id.get(id));
+            CandidatePairStats {
+                local_address: local.and_then(|c| c.address.clone()),
+                local_port: local.and_then(|c| c.port),
+                local_type: local.and_then(|c| c.candidate_type.clone()),
+                remote_address: remote.and_then(|c| c.address.clone()),
+                remote_port: remote.and_then(|c| c.port),
+                remote_type: remote.and_then(|c| c.candidate_type.clone()),
+                protocol: local.and_then(|c| c.protocol.clone()),
+                current_round_trip_time: pair.current_round_trip_time,
+                available_outgoing_bitrate: pair.available_outgoing_bitrate,
+            }
+        });
+        let round_trip_time = candidate_pair
+            .as_ref()
+            .and_then(|pair| pair.current_round_trip_time)
+            .or_else(|| outbound.iter().find_map(|stream| stream.round_trip_time));
+
+        let total = |rates: Vec<Option<f64>>| -> Option<f64> {
+            if rates.is_empty() {
+                return None;
+            }
+            rates.into_iter().sum()
+        };
+        let inbound_bitrate = total(inbound.iter().map(|stream| stream.bitrate).collect());
+        let outbound_bitrate = total(outbound.iter().map(|stream| stream.bitrate).collect());
+
+        StatsSnapshot {
+            timestamp,
+            interval_ms,
+            inbound,
+            outbound,
+            candidate_pair,
+            round_trip_time,
+            inbound_bitrate,
+            outbound_bitrate,
+        }
+    }
+}
+
+// transport の selectedCandidatePairId、無ければ selected / nominated なペア
+fn selected_pair<'a>(entries: &'a [RawStats], by_id: &HashMap<&str, &'a RawStats>) -> Option<&'a RawStats> {
+    let from_transport = entries
+        .iter()
+        .filter(|entry| entry.kind_of_stats == "transport")
+        .find_map(|transport| transport.selected_candidate_pair_id.as_deref())
+        .and_then(|id| by_id.get(id).copied());
+    from_transport.or_else(|| {
+        let pairs = || entries.iter().filter(|entry| entry.kind_of_stats == "candidate-pair");
+        pairs()
+            .find(|pair| pair.selected == Some(true))
+            .or_else(|| pairs().find(|pair| pair.nominated == Some(true) && pair.state.as_deref() == Some("succeeded")))
+    })
+}
+
+// getStats() を呼んでスナップショットを作る
+pub(crate) async fn collect(
+    peer_connection: &RtcPeerConnection,
+    previous: Option<&StatsSnapshot>,
+) -> Result<StatsSnapshot, JsValue> {
+    let report = JsFuture::from(peer_connection.get_stats()).await?;
+    // RTCStatsReport は Map と同じく values() で各統計を列挙できる
+    let values = Reflect::get(&report, &"values".into())?
+        .dyn_into::<js_sys::Function>()?
+        .call0(&report)?;
+    let mut entries = Vec::new();
+    let Some(iterator) = js_sys::try_iter(&values)? else {
+        return Err(JsValue::from_str("stats report is not iterable"));
+    };
+    for value in iterator {
+        entries.push(serde_wasm_bindgen::from_value::<RawStats>(value?)?);
+    }
+    Ok(StatsSnapshot::from_raw(entries, previous))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn rtp(kind_of_stats: &str, id: &str, timestamp: f64, bytes: f64, packets_lost: f64) -> RawStats {
+        RawStats {
+            id: id.to_string(),
+            kind_of_stats: kind_of_stats.to_string(),
+            timestamp,
+            kind: Some("video".to_string()),
+            bytes_received: Some(bytes),
+            bytes_sent: Some(bytes),
+            packets_lost: Some(packets_lost),
+            ..RawStats::default()
+        }
+    }
+
+    fn stats(kind_of_stats: &str, id: &str) -> RawStats {
+        RawStats { id: id.to_string(), kind_of_stats: kind_of_stats.to_string(), ..RawStats::default() }
+    }
+
+    #[test]
+    fn first_snapshot_has_no_rates() {
+        let snapshot = StatsSnapshot::from_raw(vec![rtp("inbound-rtp", "in", 1000.0, 5000.0, 3.0)], None);
+        assert_eq!(snapshot.interval_ms, None);
+        assert_eq!(snapshot.inbound[0].bitrate, None);
+        assert_eq!(snapshot.inbound[0].packets_lost_delta, None);
+        assert_eq!(snapshot.inbound_bitrate, None);
+        assert_eq!(snapshot.outbound_bitrate, None);
+    }
+
+    #[test]
+    fn bitrate_and_loss_from_deltas() {
+        let first = StatsSnapshot::from_raw(
+            vec![rtp("inbound-rtp", "in", 1000.0, 10_000.0, 2.0), rtp("outbound-rtp", "out", 1000.0, 0.0, 0.0)],
+            None,
+        );
+        // 2 秒で 50,000 バイト = 200 kbps
+        let second = StatsSnapshot::from_raw(
+            vec![rtp("inbound-rtp", "in", 3000.0, 60_000.0, 7.0), rtp("outbound-rtp", "out", 3000.0, 25_000.0, 0.0)],
+            Some(&first),
+        );
+        assert_eq!(second.interval_ms, Some(2000.0));
+        assert_eq!(second.inbound[0].bitrate, Some(200_000.0));
+        assert_eq!(second.inbound[0].packets_lost_delta, Some(5.0));
+        assert_eq!(second.outbound[0].bitrate, Some(100_000.0));
+        assert_eq!(second.inbound_bitrate, Some(200_000.0));
+        assert_eq!(second.outbound_bitrate, Some(100_000.0));
+    }
+
+    #[test]
+    fn counter_reset_does_not_go_negative() {
+        let first = StatsSnapshot::from_raw(vec![rtp("inbound-rtp", "in", 1000.0, 90_000.0, 40.0)], None);
+        let second = StatsSnapshot::from_raw(vec![rtp("inbound-rtp", "in", 2000.0, 1_000.0, 1.0)], Some(&first));
+        assert_eq!(second.inbound[0].bitrate, Some(0.0));
+        assert_eq!(second.inbound[0].packets_lost_delta, Some(0.0));
+    }
+
+    #[test]
+    fn new_stream_has_no_rate_until_next_snapshot() {
+        let first = StatsSnapshot::from_raw(vec![rtp("inbound-rtp", "a", 1000.0, 1_000.0, 0.0)], None);
+        let second = StatsSnapshot::from_raw(
+            vec![rtp("inbound-rtp", "a", 2000.0, 2_000.0, 0.0), rtp("inbound-rtp", "b", 2000.0, 9_000.0, 0.0)],
+            Some(&first),
+        );
+        assert_eq!(second.inbound[0].bitrate, Some(8_000.0));
+        assert_eq!(second.inbound[1].bitrate, None);
+        // 1 つでも不明なら合計も不明
+        assert_eq!(second.inbound_bitrate, None);
+    }
+
+    fn candidates() -> Vec<RawStats> {
+        let local = RawStats { address: Some("192.0.2.1".to_string()), candidate_type: Some("host".to_string()), ..stats("local-candidate", "L") };
+        let remote = RawStats { address: Some("198.51.100.1".to_string()), candidate_type: Some("relay".to_string()), ..stats("remote-candidate", "R") };
+        let pair = |id: &str, rtt: f64| RawStats {
+            local_candidate_id: Some("L".to_string()),
+            remote_candidate_id: Some("R".to_string()),
+            current_round_trip_time: Some(rtt),
+            ..stats("candidate-pair", id)
+        };
+        vec![
+            local,
+            remote,
+            pair("P1", 0.1),
+            RawStats { nominated: Some(true), state: Some("succeeded".to_string()), ..pair("P2", 0.2) },
+            RawStats { selected: Some(true), ..pair("P3", 0.3) },
+        ]
+    }
+
+    #[test]
+    fn pair_from_transport() {
+        let mut entries = candidates();
+        entries.push(RawStats { selected_candidate_pair_id: Some("P1".to_string()), ..stats("transport", "T") });
+        let snapshot = StatsSnapshot::from_raw(entries, None);
+        let pair = snapshot.candidate_pair.unwrap();
+        assert_eq!(pair.current_round_trip_time, Some(0.1));
+        assert_eq!(pair.local_address.as_deref(), Some("192.0.2.1"));
+        assert_eq!(pair.remote_type.as_deref(), Some("relay"));
+        assert_eq!(snapshot.round_trip_time, Some(0.1));
+    }
+
+    #[test]
+    fn pair_fallback_without_transport_id() {
+        // Firefox: transport に selectedCandidatePairId が無く selected が付く
+        let mut entries = candidates();
+        entries.push(stats("transport", "T"));
+        let by_id = entries.iter().map(|entry| (entry.id.as_str(), entry)).collect();
+        assert_eq!(selected_pair(&entries, &by_id).map(|pair| pair.id.as_str()), Some("P3"));
+
+        // selected も無ければ nominated かつ succeeded のペア
+        let entries: Vec<RawStats> = candidates().into_iter().filter(|entry| entry.id != "P3").collect();
+        let by_id = entries.iter().map(|entry| (entry.id.as_str(), entry)).collect();
+        assert_eq!(selected_pair(&entries, &by_id).map(|pair| pair.id.as_str()), Some("P2"));
+
+        let entries: Vec<RawStats> = candidates().into_iter().filter(|entry| entry.id == "P1").collect();
+        let by_id = entries.iter().map(|entry| (entry.id.as_str(), entry)).collect();
+        assert!(selected_pair(&entries, &by_id).is_none());
+    }
+
+    #[test]
+    fn round_trip_time_falls_back_to_remote_inbound() {
+        let remote = RawStats { kind: Some("video".to_string()), round_trip_time: Some(0.05), packets_lost: Some(4.0), ..stats("remote-inbound-rtp", "RI") };
+        let snapshot = StatsSnapshot::from_raw(vec![rtp("outbound-rtp", "out", 1000.0, 0.0, 0.0), remote], None);
+        assert!(snapshot.candidate_pair.is_none());
+        assert_eq!(snapshot.outbound[0].remote_packets_lost, Some(4.0));
+        assert_eq!(snapshot.round_trip_time, Some(0.05));
+    }
+}
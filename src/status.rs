@@ -0,0 +1,61 @@
+// on_status_change に渡す接続状態
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ConnectionStatus {
+    // シグナリングサーバーに接続した
+    SignalingConnected,
+    // シグナリングサーバーとの接続が切れた
+    SignalingDisconnected,
+    // ローカルのカメラ映像を取得した
+    LocalMediaStarted,
+    LocalMediaFailed,
+    // オファーを受け取り、アンサーを送った
+    OfferReceived,
+    // アンサーを受け取った
+    AnswerReceived,
+    // リモートの映像を受信した
+    RemoteTrackReceived,
+    // ICE が切れたので ICE restart している
+    IceReconnecting,
+    // ICE restart で接続が戻った
+    IceRestored,
+    // ICE restart を繰り返しても戻らなかった
+    IceFailed,
+    // 通話が終了した (自分の hang_up または相手の bye)
+    CallEnded,
+}
+
+impl ConnectionStatus {
+    // JSに渡す識別子
+    pub fn as_str(self) -> &'static str {
+        match self {
+            ConnectionStatus::SignalingConnected => "signalingConnected",
+            ConnectionStatus::SignalingDisconnected => "signalingDisconnected",
+            ConnectionStatus::LocalMediaStarted => "localMediaStarted",
+            ConnectionStatus::LocalMediaFailed => "localMediaFailed",
+            ConnectionStatus::OfferReceived => "offerReceived",
+            ConnectionStatus::AnswerReceived => "answerReceived",
+            ConnectionStatus::RemoteTrackReceived => "remoteTrackReceived",
+            ConnectionStatus::IceReconnecting => "reconnecting",
+            ConnectionStatus::IceRestored => "restored",
+            ConnectionStatus::IceFailed => "iceFailed",
+            ConnectionStatus::CallEnded => "callEnded",
+        }
+    }
+
+    // DOM ヘルパーが表示する文言
+    pub fn description(self) -> &'static str {
+        match self {
+            ConnectionStatus::SignalingConnected => "Status: Connected to signaling server",
+            ConnectionStatus::SignalingDisconnected => "Status: Disconnected from signaling server",
+            ConnectionStatus::LocalMediaStarted => "Status: Camera access successful",
+            ConnectionStatus::LocalMediaFailed => "Status: Camera access failed",
+            ConnectionStatus::OfferReceived => "Status: Received offer, sent answer",
+            ConnectionStatus::AnswerReceived => "Status: Received answer, connection established",
+            ConnectionStatus::RemoteTrackReceived => "Status: Remote video connected",
+            ConnectionStatus::IceReconnecting => "Status: Connection lost, reconnecting...",
+            ConnectionStatus::IceRestored => "Status: Connection restored",
+            ConnectionStatus::IceFailed => "Status: Connection failed",
+            ConnectionStatus::CallEnded => "Status: Call ended",
+        }
+    }
+}
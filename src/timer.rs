@@ -0,0 +1,24 @@
+use wasm_bindgen::prelude::*;
+
+// setTimeout の ID と、そこに渡したクロージャ
+// drop するとタイマーを止めてクロージャを解放する (once_into_js と違い、止めても漏れない)
+pub(crate) struct Timeout {
+    id: i32,
+    _callback: Closure<dyn FnMut()>,
+}
+
+impl Timeout {
+    pub fn new(delay_ms: u32, callback: impl FnOnce() + 'static) -> Result<Timeout, JsValue> {
+        let callback = Closure::once(callback);
+        let id = crate::set_timeout(callback.as_ref(), delay_ms)?;
+        Ok(Timeout { id, _callback: callback })
+    }
+}
+
+impl Drop for Timeout {
+    fn drop(&mut self) {
+        if let Some(window) = web_sys::window() {
+            window.clear_timeout_with_handle(self.id);
+        }
+    }
+}
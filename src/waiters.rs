@@ -0,0 +1,63 @@
+use std::cell::RefCell;
+use std::rc::Rc;
+use wasm_bindgen::prelude::*;
+use wasm_bindgen_futures::JsFuture;
+use crate::error::ClientError;
+use crate::timer::Timeout;
+
+type Outcome = Rc<RefCell<Option<Result<JsValue, ClientError>>>>;
+
+struct Waiter {
+    outcome: Outcome,
+    resolve: js_sys::Function,
+}
+
+// connect() / answer() のように「次にイベントが起きるまで」待つ呼び出しの一覧
+// Promise は常に resolve し、結果は Rust 側の outcome に入れる
+#[derive(Default)]
+pub(crate) struct Waiters {
+    waiting: RefCell<Vec<Waiter>>,
+}
+
+impl Waiters {
+    // settle されるか timeout_ms が過ぎるまで待つ。タイムアウト時は on_timeout を返す
+    pub async fn wait(&self, timeout_ms: u32, on_timeout: ClientError) -> Result<JsValue, ClientError> {
+        let mut resolve = None;
+        let promise = js_sys::Promise::new(&mut |resolve_fn, _reject| resolve = Some(resolve_fn));
+        let Some(resolve) = resolve else {
+            return Err(ClientError::Transport("failed to create a promise".to_string()));
+        };
+        let outcome: Outcome = Rc::new(RefCell::new(None));
+        {
+            let mut waiting = self.waiting.borrow_mut();
+            // タイムアウトした呼び出しはここで捨てる
+            waiting.retain(|waiter| waiter.outcome.borrow().is_none());
+            waiting.push(Waiter { outcome: outcome.clone(), resolve: resolve.clone() });
+        }
+
+        let timed_out = outcome.clone();
+        // 待ち終わったら drop してタイマーとクロージャを解放する
+        let _timer = Timeout::new(timeout_ms, move || {
+            if timed_out.borrow().is_none() {
+                timed_out.replace(Some(Err(on_timeout)));
+            }
+            let _ = resolve.call0(&JsValue::NULL);
+        })
+        .map_err(ClientError::transport)?;
+
+        let _ = JsFuture::from(promise).await;
+        let result = outcome.borrow().clone();
+        result.unwrap_or_else(|| Err(ClientError::Transport("wait ended without a result".to_string())))
+    }
+
+    // 待っている呼び出しを全て result で終わらせる
+    pub fn settle(&self, result: Result<JsValue, ClientError>) {
+        let waiting = std::mem::take(&mut *self.waiting.borrow_mut());
+        for waiter in waiting {
+            if waiter.outcome.borrow().is_none() {
+                waiter.outcome.replace(Some(result.clone()));
+            }
+            let _ = waiter.resolve.call0(&JsValue::NULL);
+        }
+    }
+}
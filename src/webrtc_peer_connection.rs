@@ -1,113 +1,328 @@
 use wasm_bindgen::prelude::*;
-use web_sys::{ RtcPeerConnection, RtcConfiguration, RtcPeerConnectionIceEvent, RtcSessionDescriptionInit, RtcIceCandidateInit, HtmlVideoElement, MediaStream, MediaStreamConstraints, Document, Window, RtcTrackEvent};
+use web_sys::{ RtcPeerConnection, RtcConfiguration, RtcPeerConnectionIceEvent, RtcSessionDescription, RtcSessionDescriptionInit, RtcIceCandidateInit, RtcSdpType, RtcSignalingState, MediaStream, MediaStreamTrack, RtcRtpSender, RtcRtpTransceiver, RtcRtpTransceiverDirection, RtcRtpTransceiverInit, RtcTrackEvent, RtcDataChannelEvent, RtcIceConnectionState, RtcOfferOptions};
 use js_sys::{Object, Reflect};
-use wasm_bindgen_futures::JsFuture;
-use wasm_bindgen::JsValue;
+use std::cell::{Cell, RefCell};
+use std::rc::{Rc, Weak};
+use serde::Serialize;
+use crate::signaling::{IceCandidate, SignalingMessage, TrackKind};
+use crate::codecs::{self, CodecInfo, CodecOrder, CodecPreferences};
+use crate::timer::Timeout;
+use crate::send_limits::{self, SendLimits, SendLimitsByKind};
+use crate::ice_servers::IceServerList;
+use crate::socket::{SharedSocket, SignalingSender};
+use crate::data_channel::{DataChannel, DataChannelOptions};
+use crate::stats::{self, StatsSnapshot};
+use crate::error::ClientError;
 
 
 #[wasm_bindgen]
 #[derive(Clone)]    
 pub struct WebRTCConnection {
     peer_connection: RtcPeerConnection,
-    ws: web_sys::WebSocket,
+    signaling: SignalingSender,
+    state: Rc<PeerState>,
+}
+
+type TrackHandler = Rc<dyn Fn(MediaStream)>;
+type CandidateErrorHandler = Rc<dyn Fn(&CandidateError)>;
+type DataChannelHandler = Rc<dyn Fn(DataChannel)>;
+type IceRecoveryHandler = Rc<dyn Fn(IceRecovery)>;
+type ErrorHandler = Rc<dyn Fn(ClientError)>;
+// setInterval の ID と、そこに渡したクロージャ
+type StatsTimer = (i32, Closure<dyn Fn()>);
+
+// ICE が disconnected になってから ICE restart するまでの猶予
+const ICE_DISCONNECTED_GRACE_MS: u32 = 5000;
+// connected に戻らないまま ICE restart を繰り返す上限
+const MAX_ICE_RESTARTS: u32 = 5;
+
+// ICE restart の経過
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum IceRecovery {
+    // ICE restart のオファーを送った
+    Reconnecting,
+    // ICE restart 後に接続が戻った
+    Restored,
+    // 上限まで ICE restart しても戻らなかった
+    Failed,
+}
+
+impl IceRecovery {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            IceRecovery::Reconnecting => "reconnecting",
+            IceRecovery::Restored => "restored",
+            IceRecovery::Failed => "failed",
+        }
+    }
+}
+
+// リモートの ICE candidate を追加できなかったときのエラー情報
+#[derive(Debug, Clone, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct CandidateError {
+    pub peer_id: Option<String>,
+    pub candidate: String,
+    pub sdp_mid: Option<String>,
+    pub sdp_m_line_index: Option<u16>,
+    pub message: String,
+}
+
+// handle_description の結果
+pub(crate) enum RemoteDescription {
+    // オファーが衝突したので無視した (impolite 側)
+    Ignored,
+    // 適用した。オファーだった場合は送信した answer
+    Applied(Option<RtcSessionDescriptionInit>),
+}
+
+// 受け取った offer / answer をどう扱うか (perfect negotiation)
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum DescriptionAction {
+    // impolite 側でオファーが衝突した
+    Ignore,
+    // polite 側で送信済みのオファーと衝突した。取り消してから適用する
+    Rollback,
+    // そのまま適用する
+    Apply,
+}
+
+// createOffer の完了待ち (making_offer だがまだ stable) なら取り消すオファーが無いので、そのまま適用する
+// 作成中のオファーは make_offer の stable チェックで捨てられる
+fn decide_description_action(
+    is_offer: bool,
+    polite: bool,
+    making_offer: bool,
+    setting_remote_answer_pending: bool,
+    signaling_state: RtcSignalingState,
+) -> DescriptionAction {
+    let ready_for_offer = !making_offer && (signaling_state == RtcSignalingState::Stable || setting_remote_answer_pending);
+    let offer_collision = is_offer && !ready_for_offer;
+    if !offer_collision {
+        return DescriptionAction::Apply;
+    }
+    if !polite {
+        return DescriptionAction::Ignore;
+    }
+    if signaling_state == RtcSignalingState::HaveLocalOffer {
+        DescriptionAction::Rollback
+    } else {
+        DescriptionAction::Apply
+    }
+}
+
+// クローン間で共有する状態
+#[derive(Default)]
+struct PeerState {
+    // リモートトラック受信時のハンドラー
+    track_handler: RefCell<Option<TrackHandler>>,
+    candidate_error_handler: RefCell<Option<CandidateErrorHandler>>,
+    // 相手が作ったデータチャネルを受け取るハンドラー
+    data_channel_handler: RefCell<Option<DataChannelHandler>>,
+    ice_recovery_handler: RefCell<Option<IceRecoveryHandler>>,
+    // バックグラウンドの処理 (自動ネゴシエーションなど) で起きたエラーのハンドラー
+    error_handler: RefCell<Option<ErrorHandler>>,
+    // 次のオファーを iceRestart: true で作る
+    ice_restart_pending: Cell<bool>,
+    // connected に戻るまでに行った ICE restart の回数
+    ice_restarts: Cell<u32>,
+    // disconnected の猶予タイマー
+    ice_grace_timer: RefCell<Option<Timeout>>,
+    // set_codec_preferences で指定したコーデックの優先順
+    codec_preferences: RefCell<CodecPreferences>,
+    // set_send_limits で指定した送信の上限 (ネゴシエーションのたびに適用し直す)
+    send_limits: RefCell<SendLimitsByKind>,
+    // RtcPeerConnection に登録したイベントハンドラー (close で解放する)
+    closures: RefCell<Option<PeerClosures>>,
+    // ビットレートなどの差分計算に使う前回の統計
+    last_stats: RefCell<Option<StatsSnapshot>>,
+    // start_stats_polling の setInterval
+    stats_timer: RefCell<Option<StatsTimer>>,
+    // リモート記述が設定される前に届いた ICE candidate
+    pending_candidates: RefCell<Vec<IceCandidate>>,
+    // Perfect negotiation の状態
+    // https://w3c.github.io/webrtc-pc/#perfect-negotiation-example
+    polite: Cell<bool>,
+    making_offer: Cell<bool>,
+    ignore_offer: Cell<bool>,
+    setting_remote_answer_pending: Cell<bool>,
+    // 適用したリモート記述の数 (オファー作成中に相手のオファーを受け入れたかの判定用)
+    remote_descriptions: Cell<u32>,
 }
 
 #[wasm_bindgen]
 impl WebRTCConnection{
     #[wasm_bindgen(constructor)]
-    pub fn new(ws: web_sys::WebSocket) -> Result<WebRTCConnection, JsValue> {
-        // RTCPeerConnection設定
-        // Create an RtcConfiguration object
-        console_log(&format!("start webrtc connection"));
-        let config = RtcConfiguration::new();
-
-        Reflect::set(&config, &"iceServers".into(), &js_sys::Array::of1(&get_ice_server()))?;
-
-        let peer_connection = RtcPeerConnection::new_with_configuration(&config)?;
-        // ICEイベントリスナーの設定
-        // let peer_connection_clone = peer_connection.clone();
-        let ws_sender = ws.clone(); // WebSocketをクロージャに渡す
-        let ice_candidate_closure = Closure::wrap(Box::new(move |event: RtcPeerConnectionIceEvent| {
-            if let Some(candidate) = event.candidate() {
-                console_log(&format!("ICE Candidate: {:?}", candidate));
-                // candidateをJSON文字列にしてWebSocketで送信
-                let candidate_json = js_sys::Object::new();
-                js_sys::Reflect::set(&candidate_json, &JsValue::from_str("type"), &JsValue::from_str("icecandidate")).unwrap();
-                js_sys::Reflect::set(&candidate_json, &JsValue::from_str("candidate"), &candidate).unwrap();
-                let json_str = js_sys::JSON::stringify(&candidate_json).unwrap();
-                ws_sender.send_with_str(&json_str.as_string().unwrap()).unwrap();
-            }
-        }) as Box<dyn Fn(RtcPeerConnectionIceEvent)>);
+    // ice_servers: [{ urls, username, credential }] (省略時は Google の STUN サーバー)
+    pub fn new(ws: web_sys::WebSocket, ice_servers: JsValue) -> Result<WebRTCConnection, JsValue> {
+        let ice_servers = parse_ice_servers(ice_servers)?;
+        WebRTCConnection::with_signaling(SignalingSender::new(SharedSocket::new(ws)), &ice_servers)
+    }
 
-        peer_connection.set_onicecandidate(Some(ice_candidate_closure.as_ref().unchecked_ref()));
-        ice_candidate_closure.forget();
+    // ICE サーバーを差し替える。次の ICE 収集 (ICE restart など) から使われる
+    pub fn set_ice_servers(&self, ice_servers: JsValue) -> Result<(), JsValue> {
+        let ice_servers = parse_ice_servers(ice_servers)?;
+        self.apply_ice_servers(&ice_servers)
+    }
 
+    // ルーム内の相手のピアID (1対1の接続では undefined)
+    pub fn remote_peer_id(&self) -> Option<String> {
+        self.signaling.remote_peer_id().map(str::to_string)
+    }
 
-        // ICE接続状態の変更を監視するイベントハンドラーを設定
-        let on_ice_connection_state_change = Closure::wrap(Box::new(move |event: RtcPeerConnectionIceEvent| {
-            let connection_state = Reflect::get(&event, &"target".into())
-                .and_then(|target| Reflect::get(&target, &"iceConnectionState".into()))
-                .unwrap_or(JsValue::from("unknown"));
-            
-            web_sys::console::log_1(&format!("ICE connection state changed: {:?}", connection_state).into());
-        }) as Box<dyn FnMut(_)>);
+    // 相手が決まっているか (ルームの接続、または1対1でリモート記述を受け取った後)
+    pub(crate) fn has_remote(&self) -> bool {
+        self.signaling.remote_peer_id().is_some() || self.peer_connection.remote_description().is_some()
+    }
 
-        peer_connection.set_oniceconnectionstatechange(Some(on_ice_connection_state_change.as_ref().unchecked_ref()));
-        on_ice_connection_state_change.forget(); // メモリリークを防ぐためにClosureを保持
+    // 接続を閉じ、イベントハンドラーと登録されたコールバックを全て解放する
+    pub fn close(&self) {
+        self.state.pending_candidates.borrow_mut().clear();
+        self.stop_stats_polling();
+        self.clear_ice_grace_timer();
+        PeerClosures::detach(&self.peer_connection);
+        // 実行中のクロージャ自身から close() が呼ばれることがあるので、解放は現在の処理が終わってからにする
+        let closures = self.state.closures.take();
+        wasm_bindgen_futures::spawn_local(async move {
+            drop(closures);
+        });
+        self.state.track_handler.take();
+        self.state.candidate_error_handler.take();
+        self.state.data_channel_handler.take();
+        self.state.ice_recovery_handler.take();
+        self.state.error_handler.take();
+        self.peer_connection.close();
+    }
 
-        let peer_connection_clone = peer_connection.clone();
-        let on_signaling_state_change = Closure::wrap(Box::new(move || {
-            let signaling_state = Reflect::get(&peer_connection_clone, &JsValue::from_str("signalingState"))
-                .unwrap_or_else(|_| JsValue::from_str("unknown"));
+    // リモートのメディアを受信したときに MediaStream で呼ばれる
+    pub fn on_track(&self, callback: js_sys::Function) {
+        self.set_track_handler(Rc::new(move |stream: MediaStream| {
+            let _ = callback.call1(&JsValue::NULL, &stream);
+        }));
+    }
 
-            web_sys::console::log_1(&format!("Signaling state changed: {:?}", signaling_state).into());
-        }) as Box<dyn FnMut()>);
+    // データチャネルを作る。options は { ordered, maxPacketLifeTime, maxRetransmits, protocol, negotiated, id }
+    pub fn create_data_channel(&self, label: &str, options: JsValue) -> Result<DataChannel, JsValue> {
+        let options = DataChannelOptions::from_js(options)?;
+        let channel = self
+            .peer_connection
+            .create_data_channel_with_data_channel_dict(label, &options.to_init());
+        Ok(DataChannel::new(channel))
+    }
 
-        peer_connection.set_onsignalingstatechange(Some(on_signaling_state_change.as_ref().unchecked_ref()));
-        on_signaling_state_change.forget(); // メモリリークを防ぐためにClosureを保持
+    // 相手がデータチャネルを作ったときに (DataChannel) で呼ばれる
+    pub fn on_data_channel(&self, callback: js_sys::Function) {
+        self.set_data_channel_handler(Rc::new(move |channel: DataChannel| {
+            let _ = callback.call1(&JsValue::NULL, &channel.into());
+        }));
+    }
 
-        // Add ontrack event handler to handle incoming media tracks
-        let on_track = Closure::wrap(Box::new(move |event: RtcTrackEvent| {
-            web_sys::console::log_1(&"Received remote track".into());
+    // 通話品質の統計を取得する
+    // { timestamp, intervalMs, inbound: [...], outbound: [...], candidatePair, roundTripTime, inboundBitrate, outboundBitrate }
+    // ビットレートは前回の取得からの差分で計算するので、初回は null
+    pub async fn get_stats(&self) -> Result<JsValue, JsValue> {
+        let snapshot = self.stats_snapshot().await?;
+        Ok(serde_wasm_bindgen::to_value(&snapshot)?)
+    }
 
-            let streams = event.streams();
-            if streams.length() == 0 {
-                return;
-            }
-            let remote_stream = streams.get(0);
-            let window = match web_sys::window() {
-                Some(w) => w,
-                None => return,
-            };
-            let document = match window.document() {
-                Some(d) => d,
-                None => return,
-            };
-            let video_element = match document.get_element_by_id("remoteVideo") {
-                Some(e) => e,
-                None => return,
-            };
-            let video = match video_element.dyn_into::<HtmlVideoElement>() {
-                Ok(v) => v,
-                Err(_) => return,
-            };
-            let media_stream = match remote_stream.dyn_into::<MediaStream>() {
-                Ok(m) => m,
-                Err(_) => {
-                    web_sys::console::log_1(&"Failed to cast remote stream to MediaStream".into());
+    // interval_ms ごとに統計を取得して callback(stats) を呼ぶ
+    pub fn start_stats_polling(&self, interval_ms: u32, callback: js_sys::Function) -> Result<(), JsValue> {
+        if interval_ms == 0 {
+            return Err(JsValue::from_str("interval must be greater than 0"));
+        }
+        self.stop_stats_polling();
+        // クロージャから PeerState を強参照すると循環するので Weak で持つ
+        let peer_connection = self.peer_connection.clone();
+        let state = Rc::downgrade(&self.state);
+        let tick = Closure::wrap(Box::new(move || {
+            let peer_connection = peer_connection.clone();
+            let state = state.clone();
+            let callback = callback.clone();
+            wasm_bindgen_futures::spawn_local(async move {
+                let Some(state) = state.upgrade() else {
                     return;
+                };
+                match collect_stats(&peer_connection, &state).await {
+                    Ok(snapshot) => match serde_wasm_bindgen::to_value(&snapshot) {
+                        Ok(value) => {
+                            let _ = callback.call1(&JsValue::NULL, &value);
+                        }
+                        Err(e) => report_error(&state, ClientError::Protocol(e.to_string())),
+                    },
+                    Err(e) => report_error(&state, ClientError::transport(e)),
                 }
-            };
-            video.set_src_object(Some(&media_stream));
-            web_sys::console::log_1(&"Remote video stream connected".into());
-            if let Some(status_element) = document.get_element_by_id("connectionStatus") {
-                let _ = status_element.set_text_content(Some("Status: Remote video connected"));
+            });
+        }) as Box<dyn Fn()>);
+        let window = web_sys::window().ok_or_else(|| JsValue::from_str("no window"))?;
+        let id = window.set_interval_with_callback_and_timeout_and_arguments_0(
+            tick.as_ref().unchecked_ref(),
+            interval_ms.min(i32::MAX as u32) as i32,
+        )?;
+        self.state.stats_timer.replace(Some((id, tick)));
+        Ok(())
+    }
+
+    pub fn stop_stats_polling(&self) {
+        if let Some((id, _tick)) = self.state.stats_timer.take() {
+            if let Some(window) = web_sys::window() {
+                window.clear_interval_with_handle(id);
+            }
+        }
+    }
+
+    // 自動ネゴシエーションや ICE restart など、バックグラウンドの処理が失敗したときに
+    // code プロパティ付きの Error で呼ばれる
+    pub fn on_error(&self, callback: js_sys::Function) {
+        self.set_error_handler(Rc::new(move |error: ClientError| {
+            let _ = callback.call1(&JsValue::NULL, &error.into());
+        }));
+    }
+
+    // ICE restart の経過を ("reconnecting" | "restored" | "failed") で受け取る
+    pub fn on_ice_restart(&self, callback: js_sys::Function) {
+        self.set_ice_recovery_handler(Rc::new(move |recovery: IceRecovery| {
+            let _ = callback.call1(&JsValue::NULL, &JsValue::from_str(recovery.as_str()));
+        }));
+    }
+
+    // 手動で ICE restart する (ネットワークの切り替えを検知したときなど)
+    pub fn restart_ice(&self) {
+        self.state.ice_restarts.set(0);
+        self.restart_ice_now();
+    }
+
+    // リモート記述を待っている ICE candidate の数
+    pub fn pending_candidate_count(&self) -> usize {
+        self.state.pending_candidates.borrow().len()
+    }
+
+    // ICE candidate の追加に失敗したときに { peerId, candidate, sdpMid, sdpMLineIndex, message } で呼ばれる
+    pub fn on_candidate_error(&self, callback: js_sys::Function) {
+        self.set_candidate_error_handler(Rc::new(move |error: &CandidateError| {
+            if let Ok(value) = serde_wasm_bindgen::to_value(error) {
+                let _ = callback.call1(&JsValue::NULL, &value);
             }
-        }) as Box<dyn FnMut(RtcTrackEvent)>);
+        }));
+    }
 
-        peer_connection.set_ontrack(Some(on_track.as_ref().unchecked_ref()));
-        on_track.forget();
-        Ok(WebRTCConnection { peer_connection, ws })
+    // オファーが衝突したとき、polite 側は自分のオファーを取り消して相手のオファーを受け入れる
+    pub fn set_polite(&self, polite: bool) {
+        self.state.polite.set(polite);
+    }
+
+    pub fn is_polite(&self) -> bool {
+        self.state.polite.get()
+    }
+
+    // オファーを作成して送信し、送信したオファーを返す
+    // 既にオファー作成中、または相手のオファーと衝突して送らなかった場合は null
+    pub async fn negotiate(&self) -> Result<Option<RtcSessionDescriptionInit>, ClientError> {
+        if self.state.making_offer.replace(true) {
+            return Ok(None);
+        }
+        let result = self.make_offer().await;
+        self.state.making_offer.set(false);
+        result
     }
 
 
@@ -143,71 +358,861 @@ impl WebRTCConnection{
         Ok(())
     }
 
+    // 同じ種類 (audio / video) の送信トラックを差し替える。再ネゴシエーションは不要
+    pub async fn replace_track(&self, track: MediaStreamTrack) -> Result<(), JsValue> {
+        let kind = track.kind();
+        let sender = self
+            .senders()
+            .into_iter()
+            .find(|sender| sender.track().is_some_and(|current| current.kind() == kind))
+            .ok_or_else(|| JsValue::from_str(&format!("no {} sender to replace", kind)))?;
+        wasm_bindgen_futures::JsFuture::from(sender.replace_track(Some(&track))).await?;
+        Ok(())
+    }
+
+    // ストリームの全トラックを addTrack で送信する (既に送信中のトラックは追加しない)
     pub fn add_media_stream(&self, stream: &MediaStream) -> Result<(), JsValue> {
-        let tracks = stream.get_tracks();
-        console_log(&format!("Adding {} tracks to peer connection", tracks.length()));
-        self.peer_connection.add_stream(stream); 
-        // for i in 0..tracks.length() {
-        //     let track = tracks.get(i).unchecked_into();
-        //     // ストリームの配列を作成
-        //     // let streams = js_sys::Array::new();
-        //     // streams.push(stream);
-        //     // // streamを渡す。
-        //     let streams = js_sys::Array::of1(stream);
-        //     self.peer_connection.add_stream(stream); .add_track(&track, &streams)?;
-        // }
+        let sending: Vec<String> = self
+            .senders()
+            .iter()
+            .filter_map(|sender| sender.track())
+            .map(|track| track.id())
+            .collect();
+        let tracks: Vec<MediaStreamTrack> = stream
+            .get_tracks()
+            .iter()
+            .filter_map(|track| track.dyn_into::<MediaStreamTrack>().ok())
+            .filter(|track| !sending.contains(&track.id()))
+            .collect();
+        console_log(&format!("Adding {} tracks to peer connection", tracks.len()));
+        for track in tracks {
+            self.peer_connection.add_track_0(&track, stream);
+        }
+        Ok(())
+    }
+
+    pub fn add_track(&self, track: &MediaStreamTrack, stream: &MediaStream) -> RtcRtpSender {
+        self.peer_connection.add_track_0(track, stream)
+    }
+
+    // 同じ種類のトラックを送信中なら replaceTrack で差し替え、無ければ addTrack で追加する
+    // 追加した場合は negotiationneeded で自動的に再ネゴシエーションされる
+    pub(crate) async fn send_track(&self, track: &MediaStreamTrack, stream: &MediaStream) -> Result<(), JsValue> {
+        let kind = track.kind();
+        let sending = self.senders().iter().any(|sender| sender.track().is_some_and(|current| current.kind() == kind));
+        if sending {
+            return self.replace_track(track.clone()).await;
+        }
+        if self.peer_connection.signaling_state() == RtcSignalingState::Closed {
+            return Err(JsValue::from_str("peer connection is closed"));
+        }
+        self.add_track(track, stream);
         Ok(())
     }
+
+    // send_track で追加したトラックの送信をやめる
+    pub(crate) fn remove_track(&self, track: &MediaStreamTrack) {
+        if self.peer_connection.signaling_state() == RtcSignalingState::Closed {
+            return;
+        }
+        let id = track.id();
+        for sender in self.senders() {
+            if sender.track().is_some_and(|current| current.id() == id) {
+                self.peer_connection.remove_track(&sender);
+            }
+        }
+    }
+
+    // 送信するトラックを持たないトランシーバーを追加する (受信専用の通話など)
+    // kind: "audio" / "video"、direction: "sendrecv" / "sendonly" / "recvonly" / "inactive"
+    pub fn add_transceiver(&self, kind: &str, direction: &str) -> Result<RtcRtpTransceiver, JsValue> {
+        if !matches!(kind, "audio" | "video") {
+            return Err(JsValue::from_str(&format!("unknown media kind: {}", kind)));
+        }
+        let init = RtcRtpTransceiverInit::new();
+        init.set_direction(parse_direction(direction)?);
+        Ok(self.peer_connection.add_transceiver_with_str_and_init(kind, &init))
+    }
+
+    // トラックを方向を指定して送信する
+    pub fn add_track_transceiver(
+        &self,
+        track: &MediaStreamTrack,
+        stream: &MediaStream,
+        direction: &str,
+    ) -> Result<RtcRtpTransceiver, JsValue> {
+        let init = RtcRtpTransceiverInit::new();
+        init.set_direction(parse_direction(direction)?);
+        init.set_streams(&js_sys::Array::of1(stream));
+        Ok(self.peer_connection.add_transceiver_with_media_stream_track_and_init(track, &init))
+    }
+
+    // kind ("audio" / "video") が一致する全てのトランシーバーの方向を変える
+    // 方向の変更は negotiationneeded を発生させ、自動で再ネゴシエーションされる
+    pub fn set_direction(&self, kind: &str, direction: &str) -> Result<(), JsValue> {
+        let direction = parse_direction(direction)?;
+        for transceiver in self.transceivers() {
+            if !transceiver.stopped() && transceiver.receiver().track().kind() == kind {
+                transceiver.set_direction(direction);
+            }
+        }
+        Ok(())
+    }
+
+    // ブラウザが送信できるコーデック [{ mimeType, clockRate, channels, sdpFmtpLine }]
+    pub fn codecs(&self, kind: &str) -> Result<JsValue, ClientError> {
+        available_codecs(kind)
+    }
+
+    // kind ("audio" / "video") の全トランシーバーのコーデックの優先順を設定する
+    // mimeTypes は ["video/H264", "VP8"] のように並べる。対応していないものは無視し、空なら既定に戻す
+    // 次のネゴシエーションから有効になる
+    pub fn set_codec_preferences(&self, kind: &str, mime_types: Vec<String>) -> Result<(), ClientError> {
+        let kind = parse_kind(kind)?;
+        let order = codecs::normalize(kind, mime_types).map_err(ClientError::Media)?;
+        self.set_codec_order(kind, order)
+    }
+
+    // トランシーバー単位でコーデックの優先順を設定する (set_codec_preferences の指定より優先)
+    pub fn set_transceiver_codec_preferences(&self, transceiver: &RtcRtpTransceiver, mime_types: Vec<String>) -> Result<(), ClientError> {
+        let kind = parse_kind(&transceiver.receiver().track().kind())?;
+        let order = codecs::normalize(kind, mime_types).map_err(ClientError::Media)?;
+        {
+            let mut preferences = self.state.codec_preferences.borrow_mut();
+            preferences.transceivers.retain(|(candidate, _)| !js_sys::Object::is(candidate, transceiver));
+            if !order.is_empty() {
+                preferences.transceivers.push((transceiver.clone(), order.clone()));
+            }
+        }
+        if codecs::can_set_preferences() {
+            let order = self.state.codec_preferences.borrow().for_transceiver(transceiver, kind).map(<[String]>::to_vec);
+            codecs::apply(transceiver, kind, &order.unwrap_or_default()).map_err(ClientError::media)?;
+        }
+        Ok(())
+    }
+
+    // kind ("audio" / "video") の全ての送信に上限を設定する。通話中でも再ネゴシエーションなしで反映される
+    // limits は { maxBitrate, maxFramerate, scaleResolutionDownBy, degradationPreference }
+    // 指定しなかった項目はブラウザの既定に戻る。null または {} なら制限なし
+    pub async fn set_send_limits(&self, kind: String, limits: JsValue) -> Result<(), ClientError> {
+        let kind = parse_kind(&kind)?;
+        let limits = parse_send_limits(kind, limits)?;
+        self.set_send_limits_of(kind, limits).await
+    }
+
+    // 現在の上限 (未設定なら null)
+    pub fn send_limits(&self, kind: &str) -> Result<JsValue, ClientError> {
+        let kind = parse_kind(kind)?;
+        match self.state.send_limits.borrow().get(kind) {
+            Some(limits) => serde_wasm_bindgen::to_value(limits).map_err(|e| ClientError::Protocol(e.to_string())),
+            None => Ok(JsValue::NULL),
+        }
+    }
+
+    // 1つの送信 (RTCRtpSender) だけに上限を設定する。ネゴシエーション前ならエラー
+    pub async fn set_sender_limits(&self, sender: RtcRtpSender, limits: JsValue) -> Result<(), ClientError> {
+        let kind = match sender.track() {
+            Some(track) => parse_kind(&track.kind())?,
+            None => return Err(ClientError::Media("sender has no track".to_string())),
+        };
+        let limits = parse_send_limits(kind, limits)?;
+        if !send_limits::apply(&sender, &limits).await.map_err(ClientError::media)? {
+            return Err(ClientError::Negotiation("sender parameters are not available until negotiation completes".to_string()));
+        }
+        Ok(())
+    }
+
+    pub fn transceivers(&self) -> Vec<RtcRtpTransceiver> {
+        self.peer_connection
+            .get_transceivers()
+            .iter()
+            .filter_map(|transceiver| transceiver.dyn_into().ok())
+            .collect()
+    }
+
+    pub fn senders(&self) -> Vec<RtcRtpSender> {
+        self.peer_connection
+            .get_senders()
+            .iter()
+            .filter_map(|sender| sender.dyn_into().ok())
+            .collect()
+    }
 }
 
-// #[wasm_bindgen(start)]
-// pub fn start() {
-//     wasm_bindgen_futures::spawn_local(async {
-//         if let Err(e) = start_camera().await {
-//             web_sys::console::error_1(&e);
-//         }
-//     });
-// }
+impl WebRTCConnection {
+    pub(crate) fn with_signaling(signaling: SignalingSender, ice_servers: &IceServerList) -> Result<WebRTCConnection, JsValue> {
+        // RTCPeerConnection設定
+        // Create an RtcConfiguration object
+        console_log("start webrtc connection");
+        let config = build_configuration(ice_servers)?;
 
+        let peer_connection = RtcPeerConnection::new_with_configuration(&config)?;
+        let connection = WebRTCConnection { peer_connection, signaling, state: Rc::new(PeerState::default()) };
+        let closures = PeerClosures::new(&connection);
+        closures.attach(&connection.peer_connection);
+        connection.state.closures.replace(Some(closures));
+
+        if let Some(remote_id) = connection.signaling.remote_peer_id().map(str::to_string) {
+            connection.assign_role(&remote_id);
+        }
+        Ok(connection)
+    }
 
-pub async fn start_camera(peer: WebRTCConnection) -> Result<(), JsValue> {
-    // videoタグを取得
-    let window = web_sys::window().unwrap();
-    let document = window.document().unwrap();
-    let video = document
-        .get_element_by_id("camera")
-        .unwrap()
-        .dyn_into::<HtmlVideoElement>()?;
+    // ピアIDを比較して polite / impolite を決める (小さい方が polite)
+    pub(crate) fn assign_role(&self, remote_id: &str) {
+        if let Some(local_id) = self.signaling.local_peer_id() {
+            self.state.polite.set(local_id < remote_id);
+        }
+    }
 
-    // カメラの制約を設定
-    let mut constraints = MediaStreamConstraints::new();
-    constraints.video(&JsValue::TRUE);
-    constraints.audio(&JsValue::FALSE);
+    async fn make_offer(&self) -> Result<Option<RtcSessionDescriptionInit>, ClientError> {
+        self.apply_codec_preferences()?;
+        let remote_descriptions = self.state.remote_descriptions.get();
+        let offer = if self.state.ice_restart_pending.take() {
+            let options = RtcOfferOptions::new();
+            options.set_ice_restart(true);
+            wasm_bindgen_futures::JsFuture::from(self.peer_connection.create_offer_with_rtc_offer_options(&options))
+                .await
+                .map_err(ClientError::negotiation)?
+        } else {
+            self.create_offer().await.map_err(ClientError::negotiation)?
+        };
+        // オファー作成中に相手のオファーを受け入れていたら送らない (応答済みで stable に戻っている場合も含む)
+        // まだ必要なら negotiationneeded が再び発生する
+        if self.peer_connection.signaling_state() != RtcSignalingState::Stable
+            || self.state.remote_descriptions.get() != remote_descriptions
+        {
+            return Ok(None);
+        }
+        let rtc_offer = self.with_preferred_codecs(offer.unchecked_into());
+        self.set_local_description(&rtc_offer).await.map_err(ClientError::negotiation)?;
 
-    // getUserMediaを呼び出す
-    let media_devices = window.navigator().media_devices()?;
-    let media_promise = media_devices.get_user_media_with_constraints(&constraints)?;
+        match rtc_offer.get_sdp() {
+            Some(sdp) => {
+                self.send_signal(SignalingMessage::Offer { sdp })?;
+                Ok(Some(rtc_offer))
+            }
+            None => {
+                console_log("Failed to extract SDP");
+                Ok(None)
+            }
+        }
+    }
 
-    // PromiseをFutureに変換してawait
-    let stream = JsFuture::from(media_promise).await?;
+    // 相手から受け取った offer / answer を perfect negotiation に従って適用する
+    // offer を受け入れた場合は answer を送信する。衝突して無視した場合は false を返す
+    pub(crate) async fn handle_description(&self, sdp_type: RtcSdpType, sdp: &str) -> Result<RemoteDescription, ClientError> {
+        let is_offer = sdp_type == RtcSdpType::Offer;
+        let action = decide_description_action(
+            is_offer,
+            self.state.polite.get(),
+            self.state.making_offer.get(),
+            self.state.setting_remote_answer_pending.get(),
+            self.peer_connection.signaling_state(),
+        );
 
-    // MediaStreamに変換してvideoタグにセット
-    let media_stream = stream.dyn_into::<MediaStream>()?;
-    video.set_src_object(Some(&media_stream));
+        self.state.ignore_offer.set(action == DescriptionAction::Ignore);
+        match action {
+            DescriptionAction::Ignore => {
+                console_log("Ignoring colliding offer (impolite peer)");
+                return Ok(RemoteDescription::Ignored);
+            }
+            DescriptionAction::Rollback => {
+                // polite 側: 自分のオファーを取り消す
+                console_log("Rolling back local offer (polite peer)");
+                self.set_local_description(&RtcSessionDescriptionInit::new(RtcSdpType::Rollback))
+                    .await
+                    .map_err(ClientError::negotiation)?;
+            }
+            DescriptionAction::Apply => {}
+        }
 
-    peer.add_media_stream(&media_stream)?;
+        let description = RtcSessionDescriptionInit::new(sdp_type);
+        description.set_sdp(sdp);
+        self.state.setting_remote_answer_pending.set(!is_offer);
+        let result = self.set_remote_description(&description).await;
+        if result.is_ok() {
+            self.state.remote_descriptions.set(self.state.remote_descriptions.get() + 1);
+        }
+        self.state.setting_remote_answer_pending.set(false);
+        result.map_err(ClientError::negotiation)?;
+        self.drain_pending_candidates().await;
 
-    Ok(())
+        if !is_offer {
+            self.apply_send_limits().await;
+            return Ok(RemoteDescription::Applied(None));
+        }
+        self.apply_codec_preferences()?;
+        let answer = self.create_answer().await.map_err(ClientError::negotiation)?;
+        let rtc_answer = self.with_preferred_codecs(answer.unchecked_into());
+        self.set_local_description(&rtc_answer).await.map_err(ClientError::negotiation)?;
+        self.apply_send_limits().await;
+        match rtc_answer.get_sdp() {
+            Some(sdp) => {
+                self.send_signal(SignalingMessage::Answer { sdp })?;
+                Ok(RemoteDescription::Applied(Some(rtc_answer)))
+            }
+            None => {
+                console_log("Failed to extract SDP");
+                Ok(RemoteDescription::Applied(None))
+            }
+        }
+    }
+
+    // 送信済みの answer (相手のオファーに応答して安定状態になっていれば)
+    pub(crate) fn current_answer(&self) -> Option<RtcSessionDescription> {
+        if self.peer_connection.signaling_state() != RtcSignalingState::Stable {
+            return None;
+        }
+        self.peer_connection
+            .local_description()
+            .filter(|description| description.type_() == RtcSdpType::Answer)
+    }
+
+    // リモート記述がまだ無ければ candidate を溜めておき、設定後にまとめて追加する
+    pub(crate) async fn handle_remote_candidate(&self, candidate: IceCandidate) {
+        if self.peer_connection.remote_description().is_none() {
+            console_log("Buffering ICE candidate until remote description is set");
+            self.state.pending_candidates.borrow_mut().push(candidate);
+            return;
+        }
+        self.add_remote_candidate(candidate).await;
+    }
+
+    async fn drain_pending_candidates(&self) {
+        let pending = std::mem::take(&mut *self.state.pending_candidates.borrow_mut());
+        for candidate in pending {
+            self.add_remote_candidate(candidate).await;
+        }
+    }
+
+    async fn add_remote_candidate(&self, candidate: IceCandidate) {
+        let init = RtcIceCandidateInit::new(&candidate.candidate);
+        init.set_sdp_mid(candidate.sdp_mid.as_deref());
+        init.set_sdp_m_line_index(candidate.sdp_m_line_index);
+        match self.add_ice_candidate(&init).await {
+            Ok(()) => console_log("ICE candidate added"),
+            // 無視したオファーに対応する candidate の追加エラーは握りつぶす
+            Err(_) if self.state.ignore_offer.get() => {}
+            Err(e) => self.report_candidate_error(candidate, &e),
+        }
+    }
+
+    fn report_candidate_error(&self, candidate: IceCandidate, error: &JsValue) {
+        let error = CandidateError {
+            peer_id: self.remote_peer_id(),
+            candidate: candidate.candidate,
+            sdp_mid: candidate.sdp_mid,
+            sdp_m_line_index: candidate.sdp_m_line_index,
+            message: error_message(error),
+        };
+        web_sys::console::error_1(&format!("Failed to add ICE candidate: {:?}", error).into());
+        let handler = self.state.candidate_error_handler.borrow().clone();
+        if let Some(handler) = handler {
+            handler(&error);
+        }
+    }
+
+    pub(crate) fn set_candidate_error_handler(&self, handler: CandidateErrorHandler) {
+        self.state.candidate_error_handler.replace(Some(handler));
+    }
+
+    // シグナリングサーバーへメッセージを送信
+    pub(crate) fn send_signal(&self, message: SignalingMessage) -> Result<(), ClientError> {
+        self.signaling.send(message).map_err(ClientError::signaling)
+    }
+
+    // 種類ごとの優先順を設定し、既存のトランシーバーにも適用する (空なら既定に戻す)
+    pub(crate) fn set_codec_order(&self, kind: TrackKind, order: Vec<String>) -> Result<(), ClientError> {
+        self.state
+            .codec_preferences
+            .borrow_mut()
+            .order
+            .set(kind, if order.is_empty() { None } else { Some(order) });
+        if !codecs::can_set_preferences() {
+            return Ok(());
+        }
+        for transceiver in self.transceivers_of(kind) {
+            let order = self.state.codec_preferences.borrow().for_transceiver(&transceiver, kind).map(<[String]>::to_vec);
+            codecs::apply(&transceiver, kind, &order.unwrap_or_default()).map_err(ClientError::media)?;
+        }
+        Ok(())
+    }
+
+    pub(crate) fn set_codec_orders(&self, orders: &CodecOrder) -> Result<(), ClientError> {
+        for kind in [TrackKind::Audio, TrackKind::Video] {
+            if let Some(order) = orders.get(kind) {
+                self.set_codec_order(kind, order.to_vec())?;
+            }
+        }
+        Ok(())
+    }
+
+    fn transceivers_of(&self, kind: TrackKind) -> Vec<RtcRtpTransceiver> {
+        self.transceivers()
+            .into_iter()
+            .filter(|transceiver| !transceiver.stopped() && transceiver.receiver().track().kind() == kind.as_str())
+            .collect()
+    }
+
+    // 上限を保存し、今ある送信に適用する (ネゴシエーション前の送信にはネゴシエーション後に適用する)
+    pub(crate) async fn set_send_limits_of(&self, kind: TrackKind, limits: SendLimits) -> Result<(), ClientError> {
+        self.state.send_limits.borrow_mut().set(kind, limits.clone());
+        for transceiver in self.transceivers_of(kind) {
+            send_limits::apply(&transceiver.sender(), &limits).await.map_err(ClientError::media)?;
+        }
+        Ok(())
+    }
+
+    // 新しい接続用。ネゴシエーション後に適用される
+    pub(crate) fn store_send_limits(&self, limits: &SendLimitsByKind) {
+        self.state.send_limits.replace(limits.clone());
+    }
+
+    // ネゴシエーションで送信が増えた・encodings が決まった後に上限を適用し直す
+    async fn apply_send_limits(&self) {
+        let limits = self.state.send_limits.borrow().clone();
+        if limits.is_empty() {
+            return;
+        }
+        for kind in [TrackKind::Audio, TrackKind::Video] {
+            let Some(limits) = limits.get(kind) else {
+                continue;
+            };
+            for transceiver in self.transceivers_of(kind) {
+                if let Err(e) = send_limits::apply(&transceiver.sender(), limits).await {
+                    report_error(&self.state, ClientError::media(e));
+                }
+            }
+        }
+    }
+
+    // オファー・アンサーを作る直前に、後から追加されたトランシーバーにも優先順を適用する
+    fn apply_codec_preferences(&self) -> Result<(), ClientError> {
+        if !codecs::can_set_preferences() || self.state.codec_preferences.borrow().is_empty() {
+            return Ok(());
+        }
+        for kind in [TrackKind::Audio, TrackKind::Video] {
+            for transceiver in self.transceivers_of(kind) {
+                let order = self.state.codec_preferences.borrow().for_transceiver(&transceiver, kind).map(<[String]>::to_vec);
+                if let Some(order) = order {
+                    codecs::apply(&transceiver, kind, &order).map_err(ClientError::media)?;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    // setCodecPreferences が無いブラウザでは SDP の並べ替えで優先順を伝える
+    // RTCSessionDescription の sdp は読み取り専用なので新しく作る
+    fn with_preferred_codecs(&self, description: RtcSessionDescriptionInit) -> RtcSessionDescriptionInit {
+        let preferences = self.state.codec_preferences.borrow();
+        if codecs::can_set_preferences() || preferences.is_empty() {
+            return description;
+        }
+        let Some(sdp) = description.get_sdp() else {
+            return description;
+        };
+        let reordered = codecs::reorder_sdp(&sdp, |kind, mid| preferences.for_section(kind, mid).map(<[String]>::to_vec));
+        let munged = RtcSessionDescriptionInit::new(description.get_type());
+        munged.set_sdp(&reordered);
+        munged
+    }
+
+    pub(crate) fn set_error_handler(&self, handler: ErrorHandler) {
+        self.state.error_handler.replace(Some(handler));
+    }
+
+    pub(crate) fn apply_ice_servers(&self, ice_servers: &IceServerList) -> Result<(), JsValue> {
+        let config = build_configuration(ice_servers)?;
+        self.peer_connection.set_configuration_with_configuration(&config)
+    }
+
+    pub(crate) fn set_track_handler(&self, handler: TrackHandler) {
+        self.state.track_handler.replace(Some(handler));
+    }
+
+    pub async fn stats_snapshot(&self) -> Result<StatsSnapshot, JsValue> {
+        collect_stats(&self.peer_connection, &self.state).await
+    }
+
+    fn handle_ice_state(&self) {
+        let ice_state = self.peer_connection.ice_connection_state();
+        console_log(&format!("ICE connection state changed: {:?}", ice_state));
+        match ice_state {
+            RtcIceConnectionState::Connected | RtcIceConnectionState::Completed => {
+                self.clear_ice_grace_timer();
+                if self.state.ice_restarts.replace(0) > 0 {
+                    self.report_ice_recovery(IceRecovery::Restored);
+                }
+            }
+            RtcIceConnectionState::Disconnected => {
+                // 一時的な切断はすぐ戻ることが多いので少し待つ
+                if self.state.ice_grace_timer.borrow().is_some() {
+                    return;
+                }
+                // タイマーは PeerState が持つので、クロージャからは弱参照で戻る
+                let weak = self.downgrade();
+                let on_grace_end = Timeout::new(ICE_DISCONNECTED_GRACE_MS, move || {
+                    let Some(connection) = weak.upgrade() else {
+                        return;
+                    };
+                    // 実行中のクロージャを解放しないように後で破棄する
+                    let timer = connection.state.ice_grace_timer.take();
+                    wasm_bindgen_futures::spawn_local(async move { drop(timer) });
+                    if connection.peer_connection.ice_connection_state() == RtcIceConnectionState::Disconnected {
+                        connection.restart_ice_now();
+                    }
+                });
+                match on_grace_end {
+                    Ok(timer) => {
+                        self.state.ice_grace_timer.replace(Some(timer));
+                    }
+                    Err(e) => web_sys::console::error_1(&e),
+                }
+            }
+            RtcIceConnectionState::Failed => {
+                self.clear_ice_grace_timer();
+                self.restart_ice_now();
+            }
+            RtcIceConnectionState::Closed => self.clear_ice_grace_timer(),
+            _ => {}
+        }
+    }
+
+    fn restart_ice_now(&self) {
+        let attempt = self.state.ice_restarts.get() + 1;
+        if attempt > MAX_ICE_RESTARTS {
+            self.report_ice_recovery(IceRecovery::Failed);
+            return;
+        }
+        self.state.ice_restarts.set(attempt);
+        console_log(&format!("Restarting ICE (attempt {})", attempt));
+        self.report_ice_recovery(IceRecovery::Reconnecting);
+        self.state.ice_restart_pending.set(true);
+        let connection = self.clone();
+        wasm_bindgen_futures::spawn_local(async move {
+            if let Err(e) = connection.negotiate().await {
+                report_error(&connection.state, e);
+            }
+        });
+    }
+
+    // drop でタイマーを止めてクロージャを解放する
+    fn clear_ice_grace_timer(&self) {
+        self.state.ice_grace_timer.take();
+    }
+
+    fn downgrade(&self) -> WeakConnection {
+        WeakConnection {
+            peer_connection: self.peer_connection.clone(),
+            signaling: self.signaling.clone(),
+            state: Rc::downgrade(&self.state),
+        }
+    }
+
+    fn report_ice_recovery(&self, recovery: IceRecovery) {
+        let handler = self.state.ice_recovery_handler.borrow().clone();
+        if let Some(handler) = handler {
+            handler(recovery);
+        }
+    }
+
+    pub(crate) fn set_ice_recovery_handler(&self, handler: IceRecoveryHandler) {
+        self.state.ice_recovery_handler.replace(Some(handler));
+    }
+
+    pub(crate) fn set_data_channel_handler(&self, handler: DataChannelHandler) {
+        self.state.data_channel_handler.replace(Some(handler));
+    }
+}
+
+async fn collect_stats(peer_connection: &RtcPeerConnection, state: &PeerState) -> Result<StatsSnapshot, JsValue> {
+    let previous = state.last_stats.borrow().clone();
+    let snapshot = stats::collect(peer_connection, previous.as_ref()).await?;
+    state.last_stats.replace(Some(snapshot.clone()));
+    Ok(snapshot)
+}
+
+// PeerState から自分自身を強参照しないためのハンドル
+// イベントハンドラーはこれを持ち、呼ばれたときだけ WebRTCConnection に戻す
+#[derive(Clone)]
+struct WeakConnection {
+    peer_connection: RtcPeerConnection,
+    signaling: SignalingSender,
+    state: Weak<PeerState>,
+}
+
+impl WeakConnection {
+    fn upgrade(&self) -> Option<WebRTCConnection> {
+        Some(WebRTCConnection {
+            peer_connection: self.peer_connection.clone(),
+            signaling: self.signaling.clone(),
+            state: self.state.upgrade()?,
+        })
+    }
+}
+
+// RtcPeerConnection に登録するイベントハンドラー
+struct PeerClosures {
+    on_ice_candidate: Closure<dyn Fn(RtcPeerConnectionIceEvent)>,
+    on_signaling_state_change: Closure<dyn Fn()>,
+    on_track: Closure<dyn Fn(RtcTrackEvent)>,
+    on_data_channel: Closure<dyn Fn(RtcDataChannelEvent)>,
+    on_negotiation_needed: Closure<dyn Fn()>,
+    on_ice_connection_state_change: Closure<dyn Fn()>,
+}
+
+impl PeerClosures {
+    fn new(connection: &WebRTCConnection) -> PeerClosures {
+        let weak = connection.downgrade();
+
+        // ICEイベントリスナーの設定
+        let ws_sender = connection.signaling.clone(); // シグナリングの送信口をクロージャに渡す
+        let candidate_state = Rc::downgrade(&connection.state);
+        let on_ice_candidate = Closure::wrap(Box::new(move |event: RtcPeerConnectionIceEvent| {
+            if let Some(candidate) = event.candidate() {
+                console_log(&format!("ICE Candidate: {:?}", candidate));
+                // candidateをシグナリングメッセージにしてWebSocketで送信
+                let message = SignalingMessage::IceCandidate {
+                    candidate: IceCandidate {
+                        candidate: candidate.candidate(),
+                        sdp_mid: candidate.sdp_mid(),
+                        sdp_m_line_index: candidate.sdp_m_line_index(),
+                        username_fragment: None,
+                    },
+                };
+                if let Err(e) = ws_sender.send(message) {
+                    if let Some(state) = candidate_state.upgrade() {
+                        report_error(&state, ClientError::signaling(e));
+                    }
+                }
+            }
+        }) as Box<dyn Fn(RtcPeerConnectionIceEvent)>);
+
+        let peer_connection = connection.peer_connection.clone();
+        let on_signaling_state_change = Closure::wrap(Box::new(move || {
+            let signaling_state = Reflect::get(&peer_connection, &JsValue::from_str("signalingState"))
+                .unwrap_or_else(|_| JsValue::from_str("unknown"));
+
+            web_sys::console::log_1(&format!("Signaling state changed: {:?}", signaling_state).into());
+        }) as Box<dyn Fn()>);
+
+        // Add ontrack event handler to handle incoming media tracks
+        let track_state = Rc::downgrade(&connection.state);
+        let on_track = Closure::wrap(Box::new(move |event: RtcTrackEvent| {
+            web_sys::console::log_1(&"Received remote track".into());
+
+            let streams = event.streams();
+            if streams.length() == 0 {
+                return;
+            }
+            let media_stream = match streams.get(0).dyn_into::<MediaStream>() {
+                Ok(m) => m,
+                Err(_) => {
+                    web_sys::console::log_1(&"Failed to cast remote stream to MediaStream".into());
+                    return;
+                }
+            };
+            let Some(state) = track_state.upgrade() else {
+                return;
+            };
+            let handler = state.track_handler.borrow().clone();
+            if let Some(handler) = handler {
+                handler(media_stream);
+            }
+        }) as Box<dyn Fn(RtcTrackEvent)>);
+
+        let channel_state = Rc::downgrade(&connection.state);
+        let on_data_channel = Closure::wrap(Box::new(move |event: RtcDataChannelEvent| {
+            let Some(state) = channel_state.upgrade() else {
+                return;
+            };
+            let channel = DataChannel::new(event.channel());
+            web_sys::console::log_1(&format!("Received data channel: {}", channel.label()).into());
+            let handler = state.data_channel_handler.borrow().clone();
+            if let Some(handler) = handler {
+                handler(channel);
+            }
+        }) as Box<dyn Fn(RtcDataChannelEvent)>);
+
+        // トラック追加などで再ネゴシエーションが必要になったら自動でオファーを送る
+        // 1対1の接続では、相手が決まる (リモート記述が設定される) までは offer() を待つ
+        let negotiating = weak.clone();
+        let on_negotiation_needed = Closure::wrap(Box::new(move || {
+            let Some(connection) = negotiating.upgrade() else {
+                return;
+            };
+            if !connection.has_remote() {
+                return;
+            }
+            wasm_bindgen_futures::spawn_local(async move {
+                if let Err(e) = connection.negotiate().await {
+                    report_error(&connection.state, e);
+                }
+            });
+        }) as Box<dyn Fn()>);
+
+        // ICE接続状態の変更を監視し、切れたら ICE restart する
+        let on_ice_connection_state_change = Closure::wrap(Box::new(move || {
+            if let Some(connection) = weak.upgrade() {
+                connection.handle_ice_state();
+            }
+        }) as Box<dyn Fn()>);
+
+        PeerClosures {
+            on_ice_candidate,
+            on_signaling_state_change,
+            on_track,
+            on_data_channel,
+            on_negotiation_needed,
+            on_ice_connection_state_change,
+        }
+    }
+
+    fn attach(&self, peer_connection: &RtcPeerConnection) {
+        peer_connection.set_onicecandidate(Some(self.on_ice_candidate.as_ref().unchecked_ref()));
+        peer_connection.set_onsignalingstatechange(Some(self.on_signaling_state_change.as_ref().unchecked_ref()));
+        peer_connection.set_ontrack(Some(self.on_track.as_ref().unchecked_ref()));
+        peer_connection.set_ondatachannel(Some(self.on_data_channel.as_ref().unchecked_ref()));
+        peer_connection.set_onnegotiationneeded(Some(self.on_negotiation_needed.as_ref().unchecked_ref()));
+        peer_connection.set_oniceconnectionstatechange(Some(self.on_ice_connection_state_change.as_ref().unchecked_ref()));
+    }
+
+    fn detach(peer_connection: &RtcPeerConnection) {
+        peer_connection.set_onicecandidate(None);
+        peer_connection.set_onsignalingstatechange(None);
+        peer_connection.set_ontrack(None);
+        peer_connection.set_ondatachannel(None);
+        peer_connection.set_onnegotiationneeded(None);
+        peer_connection.set_oniceconnectionstatechange(None);
+    }
+}
+
+// エラーをコンソールに出し、登録されていればハンドラーに渡す
+fn report_error(state: &PeerState, error: ClientError) {
+    web_sys::console::error_1(&error.to_string().into());
+    let handler = state.error_handler.borrow().clone();
+    if let Some(handler) = handler {
+        handler(error);
+    }
+}
+
+pub(crate) fn available_codecs(kind: &str) -> Result<JsValue, ClientError> {
+    let codecs: Vec<CodecInfo> = codecs::available(parse_kind(kind)?);
+    serde_wasm_bindgen::to_value(&codecs).map_err(|e| ClientError::Protocol(e.to_string()))
+}
+
+pub(crate) fn parse_send_limits(kind: TrackKind, limits: JsValue) -> Result<SendLimits, ClientError> {
+    if limits.is_null() || limits.is_undefined() {
+        return Ok(SendLimits::default());
+    }
+    let limits: SendLimits = serde_wasm_bindgen::from_value(limits).map_err(|e| ClientError::Protocol(e.to_string()))?;
+    limits.validate(kind).map_err(ClientError::Protocol)?;
+    Ok(limits)
+}
+
+pub(crate) fn parse_kind(kind: &str) -> Result<TrackKind, ClientError> {
+    TrackKind::parse(kind).ok_or_else(|| ClientError::Protocol(format!("unknown media kind: {}", kind)))
+}
+
+fn parse_direction(direction: &str) -> Result<RtcRtpTransceiverDirection, JsValue> {
+    match RtcRtpTransceiverDirection::from_js_value(&JsValue::from_str(direction)) {
+        Some(RtcRtpTransceiverDirection::Stopped) | None => {
+            Err(JsValue::from_str(&format!("unknown transceiver direction: {}", direction)))
+        }
+        Some(direction) => Ok(direction),
+    }
 }
 
 // ICEサーバー設定のヘルパー関数
-fn get_ice_server() -> Object {
-    let ice_server = Object::new();
-    Reflect::set(&ice_server, &"urls".into(), &"stun:stun.l.google.com:19302".into()).unwrap();
-    ice_server
+fn build_configuration(ice_servers: &IceServerList) -> Result<RtcConfiguration, JsValue> {
+    let config = RtcConfiguration::new();
+    let servers = js_sys::Array::new();
+    for server in &ice_servers.0 {
+        let ice_server = Object::new();
+        let urls: js_sys::Array = server.urls.iter().map(|url| JsValue::from_str(url)).collect();
+        Reflect::set(&ice_server, &"urls".into(), &urls)?;
+        if let Some(username) = &server.username {
+            Reflect::set(&ice_server, &"username".into(), &username.into())?;
+        }
+        if let Some(credential) = &server.credential {
+            Reflect::set(&ice_server, &"credential".into(), &credential.into())?;
+        }
+        servers.push(&ice_server);
+    }
+    Reflect::set(&config, &"iceServers".into(), &servers)?;
+    Ok(config)
+}
+
+// undefined / null ならデフォルトの ICE サーバーを使う
+pub(crate) fn parse_ice_servers(value: JsValue) -> Result<IceServerList, JsValue> {
+    let ice_servers: IceServerList = if value.is_undefined() || value.is_null() {
+        IceServerList::default()
+    } else {
+        serde_wasm_bindgen::from_value(value)?
+    };
+    ice_servers.validate().map_err(|e| JsValue::from_str(&e))?;
+    Ok(ice_servers)
+}
+
+// JSのエラー値からメッセージを取り出す
+pub(crate) fn error_message(error: &JsValue) -> String {
+    if let Some(error) = error.dyn_ref::<js_sys::Error>() {
+        return String::from(error.message());
+    }
+    error.as_string().unwrap_or_else(|| format!("{:?}", error))
 }
 
 // コンソールログのヘルパー関数
 fn console_log(message: &str) {
     web_sys::console::log_1(&message.into());
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn offer_action(polite: bool, making_offer: bool, signaling_state: RtcSignalingState) -> DescriptionAction {
+        decide_description_action(true, polite, making_offer, false, signaling_state)
+    }
+
+    #[test]
+    fn offer_in_stable_state_is_applied() {
+        for polite in [true, false] {
+            assert_eq!(offer_action(polite, false, RtcSignalingState::Stable), DescriptionAction::Apply);
+        }
+    }
+
+    #[test]
+    fn offer_while_creating_offer() {
+        // createOffer の完了待ちで、まだ stable: 取り消すものは無い
+        assert_eq!(offer_action(true, true, RtcSignalingState::Stable), DescriptionAction::Apply);
+        assert_eq!(offer_action(false, true, RtcSignalingState::Stable), DescriptionAction::Ignore);
+        // setLocalDescription 済み
+        assert_eq!(offer_action(true, true, RtcSignalingState::HaveLocalOffer), DescriptionAction::Rollback);
+        assert_eq!(offer_action(false, true, RtcSignalingState::HaveLocalOffer), DescriptionAction::Ignore);
+    }
+
+    #[test]
+    fn offer_with_sent_offer_pending() {
+        assert_eq!(offer_action(true, false, RtcSignalingState::HaveLocalOffer), DescriptionAction::Rollback);
+        assert_eq!(offer_action(false, false, RtcSignalingState::HaveLocalOffer), DescriptionAction::Ignore);
+    }
+
+    #[test]
+    fn offer_while_applying_answer_is_not_a_collision() {
+        let action = decide_description_action(true, false, false, true, RtcSignalingState::HaveLocalOffer);
+        assert_eq!(action, DescriptionAction::Apply);
+    }
+
+    #[test]
+    fn answers_are_always_applied() {
+        for polite in [true, false] {
+            for making_offer in [true, false] {
+                let action = decide_description_action(false, polite, making_offer, false, RtcSignalingState::HaveLocalOffer);
+                assert_eq!(action, DescriptionAction::Apply);
+            }
+        }
+    }
+}